@@ -0,0 +1,175 @@
+//! Lock-free buddy allocation of block indices, with one bitmap per block size.
+#![feature(allocator_api)]
+
+use rand::Rng;
+use std::alloc::Allocator;
+use std::sync::atomic::{AtomicU64, Ordering::*};
+
+/// Hands out aligned blocks of `1 << level` indices from `0..len`, for levels below `levels`.
+/// Freed blocks are merged with their buddy, except on the top level.
+pub struct BuddyTower<A: Allocator> {
+    words: Vec<AtomicU64, A>,
+    /// the first word of each level, the last entry is the end of the top level.
+    level_start: Vec<usize, A>,
+    len: usize,
+}
+
+impl<A: Allocator + Clone> BuddyTower<A> {
+    /// An empty tower for the indices `0..len`, `levels` may be at most `len.ilog2() + 1`.
+    pub fn new(len: usize, levels: usize, allocator: A) -> Self {
+        assert!(len > 0);
+        assert!(0 < levels && levels <= len.ilog2() as usize + 1);
+        let mut level_start = Vec::with_capacity_in(levels + 1, allocator.clone());
+        let mut word_count = 0;
+        for level in 0..levels {
+            level_start.push(word_count);
+            word_count += (len >> level).div_ceil(64);
+        }
+        level_start.push(word_count);
+        let mut words = Vec::with_capacity_in(word_count, allocator);
+        words.resize_with(word_count, || AtomicU64::new(0));
+        BuddyTower {
+            words,
+            level_start,
+            len,
+        }
+    }
+}
+
+impl<A: Allocator> BuddyTower<A> {
+    /// Removing from this or a higher level always fails.
+    pub fn levels(&self) -> usize {
+        self.level_start.len() - 1
+    }
+
+    fn level_words(&self, level: usize) -> &[AtomicU64] {
+        &self.words[self.level_start[level]..self.level_start[level + 1]]
+    }
+
+    /// Returns the block starting at `index`, merging it with free buddies.
+    pub fn insert(&self, mut index: usize, mut level: u32) {
+        assert!(index.is_multiple_of(1 << level));
+        assert!(index + (1 << level) <= self.len);
+        loop {
+            let block = index >> level;
+            let bit = 1 << (block % 64);
+            let buddy = 1 << ((block ^ 1) % 64);
+            let top = level as usize + 1 == self.levels();
+            let word = &self.level_words(level as usize)[block / 64];
+            let old = word
+                .fetch_update(Release, Relaxed, |x| {
+                    debug_assert!(x & bit == 0, "block inserted twice");
+                    if !top && x & buddy != 0 {
+                        Some(x & !buddy)
+                    } else {
+                        Some(x | bit)
+                    }
+                })
+                .unwrap();
+            if top || old & buddy == 0 {
+                return;
+            }
+            index &= !(1 << level);
+            level += 1;
+        }
+    }
+
+    /// Takes a block of `1 << level` indices and returns its first index.
+    /// Splits a larger block if there is none of the right size.
+    /// Looks at up to `budget` words per level, starting at a random one, so it may fail spuriously.
+    pub fn remove(&self, level: u32, rng: &mut impl Rng, budget: usize) -> Option<usize> {
+        for taken_from in level as usize..self.levels() {
+            if let Some(block) = self.take(taken_from, rng, budget) {
+                let index = block << taken_from;
+                // the upper halves are free, their buddies are part of the returned block.
+                for split in (level as usize..taken_from).rev() {
+                    let upper = (index >> split) + 1;
+                    self.level_words(split)[upper / 64].fetch_or(1 << (upper % 64), Release);
+                }
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn take(&self, level: usize, rng: &mut impl Rng, budget: usize) -> Option<usize> {
+        let words = self.level_words(level);
+        let start = rng.random_range(0..words.len());
+        for i in 0..budget.min(words.len()) {
+            let w = (start + i) % words.len();
+            if let Ok(x) =
+                words[w].fetch_update(Acquire, Relaxed, |x| (x != 0).then(|| x & (x - 1)))
+            {
+                return Some(w * 64 + x.trailing_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    /// Takes all free blocks on `level`, yielding their first indices.
+    /// Blocks inserted concurrently may or may not be yielded.
+    pub fn drain_level(&self, level: usize) -> impl Iterator<Item = usize> + '_ {
+        self.level_words(level)
+            .iter()
+            .enumerate()
+            .flat_map(move |(w, word)| {
+                let mut taken = word.swap(0, Acquire);
+                std::iter::from_fn(move || {
+                    if taken == 0 {
+                        return None;
+                    }
+                    let bit = taken.trailing_zeros() as usize;
+                    taken &= taken - 1;
+                    Some((w * 64 + bit) << level)
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuddyTower;
+    use rand::{rngs::SmallRng, SeedableRng};
+    use std::alloc::Global;
+
+    fn full(len: usize, levels: usize) -> BuddyTower<Global> {
+        let tower = BuddyTower::new(len, levels, Global);
+        for i in 0..len {
+            tower.insert(i, 0);
+        }
+        tower
+    }
+
+    #[test]
+    fn buddies_merge_up_to_the_top_level() {
+        let tower = full(12, 4);
+        assert_eq!(tower.levels(), 4);
+        assert_eq!(tower.drain_level(3).collect::<Vec<_>>(), [0]);
+        assert_eq!(tower.drain_level(2).collect::<Vec<_>>(), [8]);
+        assert_eq!(tower.drain_level(0).count(), 0);
+    }
+
+    #[test]
+    fn remove_splits_larger_blocks() {
+        let tower = full(16, 5);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut taken: Vec<_> = (0..16)
+            .map(|_| tower.remove(0, &mut rng, 1).unwrap())
+            .collect();
+        assert_eq!(tower.remove(0, &mut rng, 1), None);
+        taken.sort();
+        assert_eq!(taken, (0..16).collect::<Vec<_>>());
+        for i in taken {
+            tower.insert(i, 0);
+        }
+        assert_eq!(tower.remove(3, &mut rng, 1), Some(0));
+        assert_eq!(tower.remove(3, &mut rng, 1), Some(8));
+        assert_eq!(tower.remove(0, &mut rng, 1), None);
+    }
+
+    #[test]
+    fn blocks_never_merge_beyond_the_top_level() {
+        let tower = full(16, 3);
+        assert_eq!(tower.drain_level(2).collect::<Vec<_>>(), [0, 4, 8, 12]);
+    }
+}
@@ -0,0 +1,311 @@
+//! Per-thread logs of allocation events.
+//!
+//! Each thread appends to its own fixed size buffer, which is mapped directly from the OS so that logging never calls into an allocator.
+//! Appending is wait-free, events that do not fit into the buffer are counted and dropped.
+//! [flush] may be called from any thread and writes the events recorded since the previous flush by all threads to a file.
+//!
+//! The file starts with a [FileHeader], followed by [Event]s, both in native byte order.
+//! Events of one thread are contiguous and ordered by time.
+//!
+//! The buffer of a thread is unmapped when it exits, or by the first flush after that if it still holds events.
+
+use crate::Tier;
+use log::error;
+use std::cell::Cell;
+use std::io::Write;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Number of events each thread can hold between flushes.
+pub const EVENTS_PER_THREAD: usize = 1 << 20;
+
+pub const MAGIC: [u8; 8] = *b"VALLOG\0\0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    Alloc,
+    Free,
+    /// A value passed to [log_user].
+    User,
+    /// `value` events of the thread were dropped because its buffer was full.
+    Dropped,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FileHeader {
+    pub magic: [u8; 8],
+    pub event_size: u64,
+    pub event_count: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Event {
+    /// Time stamp counter at the time of the event.
+    pub timestamp: u64,
+    /// Sequential number of the logging thread, starting at 0.
+    pub thread: u32,
+    pub kind: EventKind,
+    /// Only meaningful for allocations and frees.
+    pub tier: Tier,
+    _pad: u16,
+    /// The size of allocations and frees, the logged value for user events.
+    pub value: u64,
+    pub address: u64,
+}
+
+const _: () = assert!(size_of::<Event>() == 32);
+
+struct ThreadLog {
+    /// Only modified while holding [FLUSH_LOCK] once the log is in [THREAD_LOGS].
+    next: *mut ThreadLog,
+    thread: u32,
+    /// Number of events written, only modified by the owning thread.
+    len: AtomicUsize,
+    dropped: AtomicU64,
+    /// Events before this index have been written to a file, their slots may be reused.
+    /// Only modified while holding [FLUSH_LOCK].
+    flushed: AtomicUsize,
+    flushed_dropped: AtomicU64,
+    /// The owning thread has exited, the log is unmapped by the next flush.
+    exited: AtomicBool,
+    events: [Event; EVENTS_PER_THREAD],
+}
+
+static THREAD_LOGS: AtomicPtr<ThreadLog> = AtomicPtr::new(ptr::null_mut());
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(0);
+static FLUSH_LOCK: Mutex<()> = Mutex::new(());
+
+#[thread_local]
+static LOCAL_LOG: Cell<*mut ThreadLog> = Cell::new(ptr::null_mut());
+
+struct LogGuard;
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        let log = LOCAL_LOG.replace(ptr::null_mut());
+        if log.is_null() {
+            return;
+        }
+        let _guard = FLUSH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let l = unsafe { &*log };
+        if l.len.load(Ordering::Relaxed) == l.flushed.load(Ordering::Relaxed)
+            && l.dropped.load(Ordering::Relaxed) == l.flushed_dropped.load(Ordering::Relaxed)
+        {
+            unsafe { unmap_log(log) };
+        } else {
+            l.exited.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+thread_local! {
+    static LOG_GUARD: LogGuard = const { LogGuard };
+}
+
+/// `tier` is usually obtained from [HeapConfig::tier](crate::HeapConfig::tier).
+pub fn log_alloc(tier: Tier, size: usize, address: *mut u8) {
+    record(EventKind::Alloc, tier, size as u64, address.addr());
+}
+
+pub fn log_free(tier: Tier, size: usize, address: *mut u8) {
+    record(EventKind::Free, tier, size as u64, address.addr());
+}
+
+pub fn log_user(value: i64) {
+    record(EventKind::User, Tier::Zero, value as u64, 0);
+}
+
+#[inline]
+fn record(kind: EventKind, tier: Tier, value: u64, address: usize) {
+    let mut log = LOCAL_LOG.get();
+    if std::hint::unlikely(log.is_null()) {
+        log = register_thread();
+        if log.is_null() {
+            return;
+        }
+    }
+    let log = unsafe { &*log };
+    let len = log.len.load(Ordering::Relaxed);
+    if len - log.flushed.load(Ordering::Acquire) >= EVENTS_PER_THREAD {
+        log.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let event = Event {
+        timestamp: unsafe { core::arch::x86_64::_rdtsc() },
+        thread: log.thread,
+        kind,
+        tier,
+        _pad: 0,
+        value,
+        address: address as u64,
+    };
+    unsafe {
+        ptr::addr_of!(log.events)
+            .cast::<Event>()
+            .cast_mut()
+            .add(len % EVENTS_PER_THREAD)
+            .write(event)
+    };
+    log.len.store(len + 1, Ordering::Release);
+}
+
+#[cold]
+fn register_thread() -> *mut ThreadLog {
+    // the buffer could not be unmapped on exit once the thread is being torn down.
+    if LOG_GUARD.try_with(|_| ()).is_err() {
+        return ptr::null_mut();
+    }
+    let log = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size_of::<ThreadLog>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if log == libc::MAP_FAILED {
+        error!("failed to map allocation log buffer");
+        return ptr::null_mut();
+    }
+    let log = log.cast::<ThreadLog>();
+    // the mapping is zeroed, so only the non-zero fields need to be set.
+    unsafe {
+        (*log).thread = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+    }
+    let mut head = THREAD_LOGS.load(Ordering::Relaxed);
+    loop {
+        unsafe { (*log).next = head };
+        match THREAD_LOGS.compare_exchange_weak(head, log, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(h) => head = h,
+        }
+    }
+    LOCAL_LOG.set(log);
+    log
+}
+
+/// Removes the log from [THREAD_LOGS] and unmaps it.
+///
+/// # Safety
+/// Must hold [FLUSH_LOCK], the owning thread must have exited.
+unsafe fn unmap_log(log: *mut ThreadLog) {
+    let next = (*log).next;
+    if THREAD_LOGS
+        .compare_exchange(log, next, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        // other threads registered since, only they touch the head.
+        let mut prev = THREAD_LOGS.load(Ordering::Acquire);
+        while (*prev).next != log {
+            prev = (*prev).next;
+        }
+        (*prev).next = next;
+    }
+    if libc::munmap(log.cast(), size_of::<ThreadLog>()) != 0 {
+        error!("failed to unmap allocation log buffer");
+    }
+}
+
+/// Writes all events recorded since the last flush to `virtual_alloc_log_<id>.bin` in the working directory.
+///
+/// Events of exited threads are included as well, their buffers are unmapped afterwards.
+pub fn flush(id: u64) {
+    let _guard = FLUSH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut path = [0u8; 64];
+    write!(&mut path[..], "virtual_alloc_log_{id}.bin\0").unwrap();
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr().cast(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        )
+    };
+    if fd < 0 {
+        error!(
+            "failed to create allocation log: {:?}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    if let Err(e) = write_logs(fd) {
+        error!("failed to write allocation log: {e:?}");
+    }
+    unsafe { libc::close(fd) };
+}
+
+fn write_logs(fd: i32) -> Result<(), std::io::Error> {
+    let mut event_count = 0;
+    let mut offset = size_of::<FileHeader>();
+    let mut log = THREAD_LOGS.load(Ordering::Acquire);
+    while let Some(l) = unsafe { log.as_ref() } {
+        let start = l.flushed.load(Ordering::Relaxed);
+        let end = l.len.load(Ordering::Acquire);
+        let events = ptr::addr_of!(l.events).cast::<Event>();
+        // the pending events wrap around the end of the buffer at most once.
+        let mut i = start;
+        while i < end {
+            let slot = i % EVENTS_PER_THREAD;
+            let count = (end - i).min(EVENTS_PER_THREAD - slot);
+            let size = count * size_of::<Event>();
+            write_at(fd, unsafe { events.add(slot) }.cast(), size, offset)?;
+            offset += size;
+            i += count;
+        }
+        event_count += end - start;
+        let dropped = l.dropped.load(Ordering::Relaxed);
+        let flushed_dropped = l.flushed_dropped.swap(dropped, Ordering::Relaxed);
+        if dropped != flushed_dropped {
+            let event = Event {
+                timestamp: unsafe { core::arch::x86_64::_rdtsc() },
+                thread: l.thread,
+                kind: EventKind::Dropped,
+                tier: Tier::Zero,
+                _pad: 0,
+                value: dropped - flushed_dropped,
+                address: 0,
+            };
+            write_at(fd, ptr::addr_of!(event).cast(), size_of::<Event>(), offset)?;
+            offset += size_of::<Event>();
+            event_count += 1;
+        }
+        l.flushed.store(end, Ordering::Release);
+        let exited = l.exited.load(Ordering::Relaxed);
+        let current = log;
+        log = l.next;
+        if exited {
+            unsafe { unmap_log(current) };
+        }
+    }
+    let header = FileHeader {
+        magic: MAGIC,
+        event_size: size_of::<Event>() as u64,
+        event_count: event_count as u64,
+    };
+    write_at(fd, ptr::addr_of!(header).cast(), size_of::<FileHeader>(), 0)
+}
+
+fn write_at(
+    fd: i32,
+    mut data: *const u8,
+    mut len: usize,
+    mut offset: usize,
+) -> Result<(), std::io::Error> {
+    while len > 0 {
+        let written = unsafe { libc::pwrite(fd, data.cast(), len, offset as libc::off_t) };
+        if written < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = written as usize;
+        data = unsafe { data.add(written) };
+        len -= written;
+        offset += written;
+    }
+    Ok(())
+}
@@ -1,11 +1,13 @@
-use crate::util::unsafe_assert;
+use crate::util::{unsafe_assert, HeapPageSize, PAGE_SIZE};
 use crate::SystemInterface;
+use log::debug;
+use std::alloc::Layout;
 use std::marker::PhantomData;
 use std::mem::{size_of, MaybeUninit};
 use std::ptr::NonNull;
-use std::sync::Mutex;
-use x86_64::structures::paging::{PageSize, PhysFrame, Size2MiB};
-use x86_64::VirtAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::*};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
 
 unsafe impl<S: PageSize, Sys: SystemInterface, const C: usize> Send for FrameList<S, Sys, C> {}
 
@@ -15,10 +17,18 @@ pub struct FrameList<S: PageSize, Sys: SystemInterface, const C: usize> {
 }
 
 #[allow(type_alias_bounds)]
-pub type FrameList2M<Sys: SystemInterface> = FrameList<Size2MiB, Sys, { 512 * 512 - 1 }>;
+pub type HeapFrameList<Sys: SystemInterface> = FrameList<HeapPageSize, Sys, HEAP_LIST_CAPACITY>;
 
+#[allow(type_alias_bounds)]
+pub type HeapFramePool<Sys: SystemInterface> = FramePool<HeapPageSize, Sys, HEAP_LIST_CAPACITY>;
+
+const HEAP_LIST_CAPACITY: usize = PAGE_SIZE / size_of::<usize>() - 2;
+
+/// Stored in the first frame of a list, the frames in `frames` follow it.
 struct ListFrame<S: PageSize, Sys: SystemInterface, const C: usize> {
     count: usize,
+    /// the tagged address of the next batch while the list is on a [FramePool] stack.
+    next: AtomicU64,
     frames: [MaybeUninit<PhysFrame<S>>; C],
     sys: PhantomData<Sys>,
 }
@@ -48,26 +58,18 @@ impl<S: PageSize, Sys: SystemInterface, const C: usize> FrameList<S, Sys, C> {
         Ok(())
     }
 
-    pub unsafe fn push_with_spill(
-        &mut self,
-        f: PhysFrame<S>,
-        dst: &Mutex<Vec<PhysFrame<S>, Sys::Alloc>>,
-    ) {
+    pub unsafe fn push_with_spill(&mut self, f: PhysFrame<S>, dst: &FramePool<S, Sys, C>) {
         if self.push(f).is_err() {
-            let mut dst = dst.lock().unwrap();
-            while self.count() > 1 {
-                dst.push(self.pop().unwrap());
-            }
+            self.release_all_but_one(dst);
             self.push(f).unwrap();
         }
     }
 
     pub const CAPACITY: usize = C + 1;
-    pub const DEFAULT_REFILL_SIZE: usize = 4;
 
     pub fn pop_with_refill(
         &mut self,
-        src: &Mutex<Vec<PhysFrame<S>, Sys::Alloc>>,
+        src: &FramePool<S, Sys, C>,
         refill_size: usize,
     ) -> Option<PhysFrame<S>> {
         assert!(refill_size > 0);
@@ -92,12 +94,30 @@ impl<S: PageSize, Sys: SystemInterface, const C: usize> FrameList<S, Sys, C> {
         Some(unsafe { head.frames[head.count].assume_init_read() })
     }
 
-    pub fn release_extra_to_vec(&mut self, dst: &Mutex<Vec<PhysFrame<S>, Sys::Alloc>>) {
-        if self.count() > 4 {
-            let mut dst = dst.lock().unwrap();
-            while self.count() > 1 {
-                dst.push(self.pop().unwrap());
-            }
+    /// The list an earlier list left in `frame`.
+    unsafe fn from_frame(sys: Sys, frame: PhysFrame<S>) -> Self {
+        let head = NonNull::new(sys.vaddr(frame.start_address()).as_mut_ptr());
+        unsafe_assert!(head.is_some());
+        FrameList { head, sys }
+    }
+
+    /// Moves all but one frame to `dst` if there are more than `limit`.
+    pub fn release_extra_to_vec(&mut self, dst: &FramePool<S, Sys, C>, limit: usize) {
+        if self.count() > limit {
+            self.release_all_but_one(dst);
+        }
+    }
+
+    /// Hands the whole list to `dst` as one batch, except for one frame which starts a new list.
+    fn release_all_but_one(&mut self, dst: &FramePool<S, Sys, C>) {
+        let Some(keep) = self.pop() else { return };
+        self.release_all_to_vec(dst);
+        unsafe { self.push(keep).unwrap() };
+    }
+
+    pub fn release_all_to_vec(&mut self, dst: &FramePool<S, Sys, C>) {
+        if self.head.is_some() {
+            dst.give(std::mem::replace(self, FrameList::new(self.sys)));
         }
     }
 
@@ -109,19 +129,319 @@ impl<S: PageSize, Sys: SystemInterface, const C: usize> FrameList<S, Sys, C> {
         }
     }
 
+    /// Takes frames from `src` until there are `target_count`, preferring those of the current NUMA node.
     pub fn steal_from_vec(
         &mut self,
-        src: &Mutex<Vec<PhysFrame<S>, Sys::Alloc>>,
+        src: &FramePool<S, Sys, C>,
         target_count: usize,
     ) -> Option<()> {
         if self.count() >= target_count {
             return Some(());
         }
         assert!(target_count < Self::CAPACITY);
-        let mut src = src.lock().unwrap();
-        while self.count() < target_count {
-            unsafe { self.push(src.pop()?).unwrap() };
+        let node = if cfg!(feature = "numa") {
+            self.sys.current_node()
+        } else {
+            0
+        };
+        src.take(node, target_count - self.count(), |frame| unsafe {
+            self.push(frame).unwrap()
+        })
+    }
+}
+
+/// A lock-free stack of frame lists, each of which is handed over as a whole.
+struct BatchStack {
+    /// frame number of the top list, see [pack_head], 0 if the stack is empty.
+    head: AtomicU64,
+    /// frames in all lists on the stack.
+    pooled: AtomicUsize,
+}
+
+/// NUMA nodes a pool supports.
+const MAX_NODES: usize = 64;
+
+/// physical addresses have at most 52 bits.
+const PHYS_ADDR_BITS: u32 = 52;
+
+/// The frame number of a list occupies the low bits of a stack head, a change counter against ABA the rest.
+/// That leaves 24 bits for the counter with 4KiB frames and 33 bits with 2MiB frames.
+const fn frame_bits<S: PageSize>() -> u32 {
+    PHYS_ADDR_BITS - S::SIZE.trailing_zeros()
+}
+
+fn pack_head<S: PageSize>(paddr: u64, tag: u64) -> u64 {
+    (paddr >> S::SIZE.trailing_zeros()) | (tag << frame_bits::<S>())
+}
+
+fn head_address<S: PageSize>(head: u64) -> u64 {
+    (head & ((1 << frame_bits::<S>()) - 1)) << S::SIZE.trailing_zeros()
+}
+
+/// The counter of `head` plus one, wrapping around.
+fn next_tag<S: PageSize>(head: u64) -> u64 {
+    (head >> frame_bits::<S>()) + 1
+}
+
+/// The frames not cached by any handle, kept in one pool per NUMA node.
+/// Once they run dry, more frames are claimed from the system up to a limit.
+pub struct FramePool<S: PageSize, Sys: SystemInterface, const C: usize> {
+    nodes: Vec<BatchStack, Sys::Alloc>,
+    /// the order in which each node takes frames from the others, see `HeapConfig::node_fallback`.
+    fallback: Option<&'static [&'static [usize]]>,
+    /// frames claimed from the system so far.
+    claimed: AtomicUsize,
+    max_frames: usize,
+    growth_size: usize,
+    sys: Sys,
+    _list: PhantomData<ListFrame<S, Sys, C>>,
+}
+
+impl<S: PageSize, Sys: SystemInterface, const C: usize> FramePool<S, Sys, C> {
+    /// Claims `frame_count` frames right away, spread evenly across the nodes.
+    pub fn new(
+        sys: Sys,
+        frame_count: usize,
+        max_frames: usize,
+        growth_size: usize,
+        fallback: Option<&'static [&'static [usize]]>,
+    ) -> Self {
+        assert!(frame_count <= max_frames);
+        assert!(S::SIZE >= Size4KiB::SIZE);
+        let (node_count, fallback) = if cfg!(feature = "numa") {
+            (sys.numa_node_count(), fallback)
+        } else {
+            (1, None)
+        };
+        assert!(node_count > 0 && node_count <= MAX_NODES);
+        if let Some(fallback) = fallback {
+            assert_eq!(fallback.len(), node_count);
+            for (node, order) in fallback.iter().enumerate() {
+                assert!(order.iter().all(|&n| n < node_count && n != node));
+            }
+        }
+        let mut nodes = Vec::with_capacity_in(node_count, sys.allocator());
+        for _ in 0..node_count {
+            nodes.push(BatchStack {
+                head: AtomicU64::new(0),
+                pooled: AtomicUsize::new(0),
+            });
+        }
+        let pool = FramePool {
+            nodes,
+            fallback,
+            claimed: AtomicUsize::new(frame_count),
+            max_frames,
+            growth_size,
+            sys,
+            _list: PhantomData,
+        };
+        let mut claimed = 0;
+        pool.put_back(|| {
+            (claimed < frame_count).then(|| {
+                claimed += 1;
+                pool.claim(claimed % node_count)
+            })
+        });
+        pool
+    }
+
+    /// Frames currently in the pools of all nodes.
+    pub fn pooled(&self) -> usize {
+        self.nodes.iter().map(|n| n.pooled.load(Relaxed)).sum()
+    }
+
+    /// Takes `count` frames, first from `node`, then from the other nodes in fallback order.
+    /// Only once all of them are empty, more frames are claimed from the system.
+    /// Returns None if the limit is reached before `count` frames are found.
+    pub fn take(
+        &self,
+        node: usize,
+        count: usize,
+        mut push: impl FnMut(PhysFrame<S>),
+    ) -> Option<()> {
+        let mut missing = count;
+        for n in self.search_order(node) {
+            while let Some(mut list) = self.pop_batch(n) {
+                while missing > 0 {
+                    let Some(frame) = list.pop() else { break };
+                    push(frame);
+                    missing -= 1;
+                }
+                if list.count() > 0 {
+                    self.push_batch(n, list);
+                }
+                if missing == 0 {
+                    return Some(());
+                }
+            }
+        }
+        self.grow(node, missing, push)
+    }
+
+    /// Adds `list` to the pool as a whole if all of its frames are on the same node.
+    fn give(&self, mut list: FrameList<S, Sys, C>) {
+        let Some(head) = list.head else { return };
+        let paddr = self.sys.paddr(VirtAddr::from_ptr(head.as_ptr()));
+        let node = self.node_of(paddr);
+        let entries = unsafe {
+            let head = head.as_ref();
+            &head.frames[..head.count]
+        };
+        let mixed = self.nodes.len() > 1
+            && entries
+                .iter()
+                .any(|f| self.node_of(unsafe { f.assume_init_ref() }.start_address()) != node);
+        if mixed {
+            self.put_back(|| list.pop());
+        } else {
+            self.push_batch(node, list);
+        }
+    }
+
+    /// Adds frames to the pools of their nodes until `frames` returns None.
+    pub fn put_back(&self, mut frames: impl FnMut() -> Option<PhysFrame<S>>) {
+        // on the stack, as this may run inside the process allocator.
+        let mut lists: [_; MAX_NODES] = std::array::from_fn(|_| FrameList::new(self.sys));
+        while let Some(frame) = frames() {
+            let node = self.node_of(frame.start_address());
+            let list = &mut lists[node];
+            if unsafe { list.push(frame) }.is_err() {
+                self.push_batch(node, std::mem::replace(list, FrameList::new(self.sys)));
+                unsafe { list.push(frame).unwrap() };
+            }
+        }
+        for (node, list) in lists.into_iter().enumerate().take(self.nodes.len()) {
+            if list.count() > 0 {
+                self.push_batch(node, list);
+            }
+        }
+    }
+
+    fn push_batch(&self, node: usize, list: FrameList<S, Sys, C>) {
+        let stack = &self.nodes[node];
+        let count = list.count();
+        let head = list.head.unwrap();
+        let paddr = self.sys.paddr(VirtAddr::from_ptr(head.as_ptr())).as_u64();
+        unsafe_assert!(paddr != 0 && paddr < 1 << PHYS_ADDR_BITS);
+        let next = unsafe { &head.as_ref().next };
+        let mut top = stack.head.load(Relaxed);
+        loop {
+            next.store(top, Relaxed);
+            let new = pack_head::<S>(paddr, next_tag::<S>(top));
+            match stack.head.compare_exchange_weak(top, new, Release, Relaxed) {
+                Ok(_) => break,
+                Err(actual) => top = actual,
+            }
+        }
+        stack.pooled.fetch_add(count, Relaxed);
+    }
+
+    fn pop_batch(&self, node: usize) -> Option<FrameList<S, Sys, C>> {
+        let stack = &self.nodes[node];
+        let mut top = stack.head.load(Acquire);
+        loop {
+            let paddr = head_address::<S>(top);
+            if paddr == 0 {
+                return None;
+            }
+            let frame = PhysFrame::from_start_address(PhysAddr::new(paddr)).unwrap();
+            let list = unsafe { FrameList::<S, Sys, C>::from_frame(self.sys, frame) };
+            // Another thread may pop the list first and reuse or even free its frame, so this may read anything.
+            // Reading is still safe, as freed frames stay readable through the direct map, see [SystemInterface::free_physical].
+            // The value is discarded then: any push or pop in between changes the counter in `top`, so the exchange fails,
+            // unless the counter wraps around during this single read.
+            let next = unsafe { list.head.unwrap().as_ref().next.load(Relaxed) };
+            let new = pack_head::<S>(head_address::<S>(next), next_tag::<S>(top));
+            match stack.head.compare_exchange_weak(top, new, Acquire, Acquire) {
+                Ok(_) => {
+                    stack.pooled.fetch_sub(list.count(), Relaxed);
+                    return Some(list);
+                }
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+    /// The node whose pool the frame at `addr` belongs to.
+    fn node_of(&self, addr: PhysAddr) -> usize {
+        if self.nodes.len() > 1 {
+            self.sys.frame_node(addr)
+        } else {
+            0
+        }
+    }
+
+    fn search_order(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let node_count = self.nodes.len();
+        let default = (1..node_count).map(move |i| (node + i) % node_count);
+        let (configured, default) = match self.fallback {
+            Some(fallback) => (Some(fallback[node].iter().copied()), None),
+            None => (None, Some(default)),
+        };
+        std::iter::once(node)
+            .chain(configured.into_iter().flatten())
+            .chain(default.into_iter().flatten())
+    }
+
+    /// Claims a multiple of `growth_size` frames on `node`, passes `missing` of them to `push` and pools the rest.
+    /// Returns None once the limit is reached.
+    #[cold]
+    fn grow(&self, node: usize, missing: usize, mut push: impl FnMut(PhysFrame<S>)) -> Option<()> {
+        let mut count = 0;
+        let _ = self.claimed.fetch_update(Relaxed, Relaxed, |claimed| {
+            count = missing
+                .next_multiple_of(self.growth_size)
+                .min(self.max_frames - claimed);
+            (count > 0).then_some(claimed + count)
+        });
+        if count == 0 {
+            return None;
+        }
+        debug!("claiming {count} more frames on node {node}");
+        for _ in 0..count.min(missing) {
+            push(self.claim(node));
+        }
+        let mut extra = count.saturating_sub(missing);
+        self.put_back(|| {
+            (extra > 0).then(|| {
+                extra -= 1;
+                self.claim(node)
+            })
+        });
+        (count >= missing).then_some(())
+    }
+
+    /// Returns pooled frames to the system until at most `keep` are left across all nodes.
+    /// Frames taken or returned concurrently may make it release fewer.
+    /// Returns the number of frames released.
+    pub fn release(&self, keep: usize) -> usize {
+        let layout = Layout::from_size_align(S::SIZE as usize, S::SIZE as usize).unwrap();
+        let mut excess = self.pooled().saturating_sub(keep);
+        let mut released = 0;
+        for node in (0..self.nodes.len()).rev() {
+            while excess > 0 {
+                let Some(mut list) = self.pop_batch(node) else {
+                    break;
+                };
+                while excess > 0 {
+                    let Some(frame) = list.pop() else { break };
+                    unsafe { self.sys.free_physical(frame.start_address(), layout) };
+                    excess -= 1;
+                    released += 1;
+                }
+                if list.count() > 0 {
+                    self.push_batch(node, list);
+                }
+            }
         }
-        Some(())
+        self.claimed.fetch_sub(released, Relaxed);
+        released
+    }
+
+    /// Claims a single frame from the system, whose count has already been added to `claimed`.
+    fn claim(&self, node: usize) -> PhysFrame<S> {
+        let layout = Layout::from_size_align(S::SIZE as usize, S::SIZE as usize).unwrap();
+        PhysFrame::from_start_address(self.sys.allocate_physical_on(layout, node)).unwrap()
     }
 }
@@ -0,0 +1,291 @@
+use crate::myalloc::{placed_size, GlobalData, LocalData};
+use crate::{SystemInterface, TestAlloc};
+use libc::{MAP_ANONYMOUS, MAP_NORESERVE, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::{Cell, SyncUnsafeCell, UnsafeCell};
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+type ThreadLocalData<S> = LocalData<S, &'static GlobalData<S>>;
+
+/// A [GlobalAlloc] using one [LocalData] per thread and a lazily initialized [GlobalData].
+///
+/// Only a single instance may be used per process.
+/// Allocations made from within the allocator, such as those [GlobalData::new] makes through [SystemInterface::Alloc],
+/// are served from a separate 4GiB bootstrap region. It is reserved on first use and never reclaimed, frees into it are ignored.
+/// Other frees made from within the allocator are deferred until the thread next enters it, see [PENDING_FREES].
+pub struct VirtualGlobalAlloc<S: SystemInterface> {
+    make_global: fn() -> GlobalData<S>,
+    init_state: AtomicUsize,
+    global: SyncUnsafeCell<MaybeUninit<GlobalData<S>>>,
+    seed: AtomicU64,
+}
+
+impl<S: SystemInterface + 'static> VirtualGlobalAlloc<S>
+where
+    GlobalData<S>: Sync,
+{
+    /// `make_global` is called once, on the first allocation.
+    ///
+    /// # Safety
+    /// The allocator must not be moved or dropped once it has been used, e.g. by placing it in a static.
+    pub const unsafe fn new(make_global: fn() -> GlobalData<S>) -> Self {
+        VirtualGlobalAlloc {
+            make_global,
+            init_state: AtomicUsize::new(0),
+            global: SyncUnsafeCell::new(MaybeUninit::uninit()),
+            seed: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn global(&self) -> &'static GlobalData<S> {
+        if std::hint::unlikely(self.init_state.load(Ordering::Acquire) != 2) {
+            self.init_global();
+        }
+        unsafe { &*(self.global.get() as *const GlobalData<S>) }
+    }
+
+    #[cold]
+    fn init_global(&self) {
+        if self
+            .init_state
+            .compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            let global = (self.make_global)();
+            unsafe { (*self.global.get()).write(global) };
+            self.init_state.store(2, Ordering::Release);
+        } else {
+            while self.init_state.load(Ordering::Acquire) != 2 {
+                std::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Like [GlobalData::allocation_size], but also accepts allocations from the bootstrap region.
+    ///
+    /// # Safety
+    /// ptr must be a live allocation of this allocator with a nonzero size.
+    #[cfg(feature = "sizeless_free")]
+    pub unsafe fn allocation_size(&self, ptr: *mut u8) -> usize {
+        if std::hint::unlikely(is_bootstrap(ptr)) {
+            return ptr.cast::<usize>().sub(1).read_unaligned();
+        }
+        self.global().allocation_size(NonNull::new_unchecked(ptr))
+    }
+
+    fn with_local<R>(&self, f: impl FnOnce(&mut ThreadLocalData<S>) -> R) -> R {
+        const {
+            assert!(size_of::<ThreadLocalData<S>>() <= size_of::<LocalStorage>());
+            assert!(align_of::<ThreadLocalData<S>>() <= align_of::<LocalStorage>());
+        }
+        let data = LOCAL.data.get().cast::<ThreadLocalData<S>>();
+        let f = |l: &mut ThreadLocalData<S>| {
+            let ret = f(l);
+            if std::hint::unlikely(LOCAL.pending_len.get() != 0) {
+                drain_pending(l);
+            }
+            ret
+        };
+        match LOCAL.state.get() {
+            LocalState::Live => f(unsafe { &mut *data }),
+            LocalState::Uninit => {
+                let global = self.global();
+                let seed = self.seed.fetch_add(1, Ordering::Relaxed);
+                if LOCAL_GUARD.try_with(|_| ()).is_err() {
+                    // thread is shutting down, no destructor can be registered anymore.
+                    return f(&mut LocalData::new(seed, global));
+                }
+                unsafe { data.write(LocalData::new(seed, global)) };
+                LOCAL.drop_local.set(Some(drop_local::<S>));
+                LOCAL.state.set(LocalState::Live);
+                f(unsafe { &mut *data })
+            }
+            LocalState::Destroyed => {
+                let seed = self.seed.fetch_add(1, Ordering::Relaxed);
+                f(&mut LocalData::new(seed, self.global()))
+            }
+        }
+    }
+}
+
+unsafe impl<S: SystemInterface + 'static> GlobalAlloc for VirtualGlobalAlloc<S>
+where
+    GlobalData<S>: Sync,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if std::hint::unlikely(LOCAL.in_allocator.replace(true)) {
+            return bootstrap_alloc(layout);
+        }
+        let ret = self.with_local(|l| l.alloc(layout));
+        LOCAL.in_allocator.set(false);
+        ret.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if std::hint::unlikely(is_bootstrap(ptr)) {
+            return;
+        }
+        if std::hint::unlikely(LOCAL.in_allocator.replace(true)) {
+            // the local data is already borrowed further up the stack.
+            defer_free(ptr, placed_size(layout));
+            return;
+        }
+        self.with_local(|l| l.dealloc(NonNull::new_unchecked(ptr), placed_size(layout)));
+        LOCAL.in_allocator.set(false);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if std::hint::unlikely(is_bootstrap(ptr) || LOCAL.in_allocator.get()) {
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+        LOCAL.in_allocator.set(true);
+        let ret = self.with_local(|l| {
+            l.realloc(NonNull::new_unchecked(ptr), placed_size(layout), new_layout)
+        });
+        LOCAL.in_allocator.set(false);
+        ret.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LocalState {
+    Uninit,
+    Live,
+    Destroyed,
+}
+
+type LocalStorage = MaybeUninit<[u64; 16]>;
+
+/// Frees made from within the allocator that did not fit are leaked.
+const PENDING_FREES: usize = 16;
+
+struct LocalSlot {
+    state: Cell<LocalState>,
+    in_allocator: Cell<bool>,
+    /// frees made from within the allocator, as pointer and placed size, made once it returns.
+    pending: UnsafeCell<[(*mut u8, usize); PENDING_FREES]>,
+    pending_len: Cell<usize>,
+    drop_local: Cell<Option<unsafe fn(*mut LocalStorage)>>,
+    data: UnsafeCell<LocalStorage>,
+}
+
+#[thread_local]
+static LOCAL: LocalSlot = LocalSlot {
+    state: Cell::new(LocalState::Uninit),
+    in_allocator: Cell::new(false),
+    pending: UnsafeCell::new([(ptr::null_mut(), 0); PENDING_FREES]),
+    pending_len: Cell::new(0),
+    drop_local: Cell::new(None),
+    data: UnsafeCell::new(MaybeUninit::uninit()),
+};
+
+unsafe fn drop_local<S: SystemInterface + 'static>(data: *mut LocalStorage)
+where
+    GlobalData<S>: Sync,
+{
+    let data = data.cast::<ThreadLocalData<S>>();
+    LOCAL.in_allocator.set(true);
+    drain_pending(&mut *data);
+    ptr::drop_in_place(data);
+    LOCAL.in_allocator.set(false);
+}
+
+fn defer_free(ptr: *mut u8, size: usize) {
+    let len = LOCAL.pending_len.get();
+    if size == 0 || len == PENDING_FREES {
+        return;
+    }
+    unsafe { (*LOCAL.pending.get())[len] = (ptr, size) };
+    LOCAL.pending_len.set(len + 1);
+}
+
+/// Frees deferred by [defer_free], including those deferred while draining.
+#[cold]
+fn drain_pending<S: SystemInterface + 'static>(l: &mut ThreadLocalData<S>)
+where
+    GlobalData<S>: Sync,
+{
+    while let Some(len) = LOCAL.pending_len.get().checked_sub(1) {
+        LOCAL.pending_len.set(len);
+        let (ptr, size) = unsafe { (*LOCAL.pending.get())[len] };
+        unsafe { l.dealloc(NonNull::new_unchecked(ptr), size) };
+    }
+}
+
+struct LocalGuard;
+
+impl Drop for LocalGuard {
+    fn drop(&mut self) {
+        if let Some(drop_local) = LOCAL.drop_local.take() {
+            LOCAL.state.set(LocalState::Destroyed);
+            unsafe { drop_local(LOCAL.data.get()) };
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL_GUARD: LocalGuard = const { LocalGuard };
+}
+
+const BOOTSTRAP_SIZE: usize = 1 << 32;
+static BOOTSTRAP_START: AtomicUsize = AtomicUsize::new(0);
+static BOOTSTRAP_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+fn is_bootstrap(ptr: *mut u8) -> bool {
+    let start = BOOTSTRAP_START.load(Ordering::Relaxed);
+    start != 0 && ptr.addr().wrapping_sub(start) < BOOTSTRAP_SIZE
+}
+
+/// Each allocation is preceded by its size.
+#[cold]
+fn bootstrap_alloc(layout: Layout) -> *mut u8 {
+    let mut start = BOOTSTRAP_START.load(Ordering::Acquire);
+    if start == 0 {
+        let p = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                BOOTSTRAP_SIZE,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if p == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        start = match BOOTSTRAP_START.compare_exchange(
+            0,
+            p.addr(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => p.addr(),
+            Err(other) => {
+                unsafe { libc::munmap(p, BOOTSTRAP_SIZE) };
+                other
+            }
+        };
+    }
+    let mut offset = 0;
+    let reserved = BOOTSTRAP_NEXT.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+        offset = (start + next + size_of::<usize>()).next_multiple_of(layout.align()) - start;
+        let end = offset + layout.size();
+        (end <= BOOTSTRAP_SIZE).then_some(end)
+    });
+    if reserved.is_err() {
+        return ptr::null_mut();
+    }
+    let ptr = ptr::with_exposed_provenance_mut::<u8>(start + offset);
+    unsafe { ptr.cast::<usize>().sub(1).write_unaligned(layout.size()) };
+    ptr
+}
@@ -9,21 +9,59 @@
 #![feature(likely_unlikely)]
 #![feature(unsafe_cell_access)]
 
+#[cfg(feature = "alloc_log")]
+pub mod alloc_log;
 mod frame_list;
+mod global_alloc;
+mod local_allocator;
+#[cfg(feature = "sizeless_free")]
+pub mod malloc;
 mod myalloc;
 mod quantum_address;
+mod simulated_system_interface;
 mod system_interface;
 mod util;
 
+#[cfg(any(
+    feature = "global_api_clib",
+    feature = "local_api_clib",
+    all(feature = "malloc_api_clib", not(feature = "linux_preload"))
+))]
+mod osv_system_interface;
+#[cfg(any(feature = "global_api_clib", feature = "local_api_clib"))]
+mod static_global_data;
+
 #[cfg(feature = "global_api_clib")]
 mod static_lib_global;
 
+#[cfg(feature = "local_api_clib")]
+mod static_lib_local;
+
+#[cfg(feature = "malloc_api_clib")]
+mod static_lib_malloc;
+
+#[cfg(feature = "linux_system_interface")]
+mod linux_system_interface;
+
+#[cfg(feature = "linux_preload")]
+mod linux_preload;
+
 use std::{alloc::Layout, ptr::NonNull};
 
-pub use myalloc::{GlobalData, LocalData};
+pub use global_alloc::VirtualGlobalAlloc;
+pub use local_allocator::LocalAllocator;
+pub use myalloc::{placed_size, GlobalData, GlobalDataBuilder, HeapConfig, LocalData, Tier};
+pub use simulated_system_interface::{
+    PageTableSimulation, SimulatedSystemInterface, SimulationEvent,
+};
 pub use system_interface::SystemInterface;
+pub use util::HeapPageSize;
+
+#[cfg(feature = "linux_system_interface")]
+pub use linux_system_interface::LinuxSystemInterface;
 
 pub unsafe trait TestAlloc: Send {
     unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>>;
+    /// `size` is the [placed_size] of the layout `ptr` was allocated with.
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, size: usize);
 }
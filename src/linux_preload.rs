@@ -0,0 +1,48 @@
+use crate::static_lib_malloc::{physical_size, virtual_size};
+use crate::{GlobalData, LinuxSystemInterface};
+use std::cell::SyncUnsafeCell;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static SYS: SyncUnsafeCell<Option<LinuxSystemInterface>> = SyncUnsafeCell::new(None);
+static FORK_SNAPSHOT: AtomicI32 = AtomicI32::new(-1);
+
+/// Called on the first allocation, which may happen while the dynamic loader is still starting the process.
+/// Allocations made from here on through the system allocator end up in our `malloc` and are served by the bootstrap region.
+pub fn make_global() -> GlobalData<LinuxSystemInterface> {
+    let physical_size = physical_size();
+    let sys = LinuxSystemInterface::new(physical_size);
+    unsafe {
+        *SYS.get() = Some(sys);
+        libc::pthread_atfork(
+            Some(before_fork),
+            Some(after_fork_parent),
+            Some(after_fork_child),
+        );
+    }
+    GlobalData::new(sys, physical_size, virtual_size())
+}
+
+/// The child gets a copy of the heap as it was at the time of the fork.
+/// The copy is made eagerly, so every fork copies all memory the heap has touched and not released since,
+/// even if the child calls `exec` right away.
+/// Modifications made by other threads of the parent while the copy is made may or may not be visible in the child.
+/// Locks held by other threads during the fork are not released in the child.
+extern "C" fn before_fork() {
+    if let Some(sys) = unsafe { *SYS.get() } {
+        FORK_SNAPSHOT.store(sys.snapshot(), Ordering::Relaxed);
+    }
+}
+
+extern "C" fn after_fork_parent() {
+    let snapshot = FORK_SNAPSHOT.swap(-1, Ordering::Relaxed);
+    if snapshot >= 0 {
+        unsafe { libc::close(snapshot) };
+    }
+}
+
+extern "C" fn after_fork_child() {
+    let snapshot = FORK_SNAPSHOT.swap(-1, Ordering::Relaxed);
+    if let Some(sys) = unsafe { *SYS.get() } {
+        unsafe { sys.switch_to_snapshot(snapshot) };
+    }
+}
@@ -0,0 +1,599 @@
+use libc::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, MFD_CLOEXEC, PROT_NONE,
+    PROT_READ, PROT_WRITE,
+};
+use log::debug;
+use std::alloc::{Layout, System};
+use std::ops::Range;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::util::{
+    arena_hint, lower_half_end, page_from_addr, vaddr_unchecked, HeapPageSize, PAGE_SIZE,
+};
+use crate::SystemInterface;
+
+/// A [SystemInterface] for unprivileged Linux processes.
+///
+/// Physical frames are emulated as offsets into a memfd.
+/// The whole memfd is mapped once as the direct map, so frames remain accessible via [SystemInterface::vaddr].
+/// Mapping a frame into the heap maps the same memfd offset a second time using `MAP_FIXED`.
+/// The first frame of the memfd is never handed out, so physical addresses are non-zero.
+/// It holds the shared state of the interface instead.
+#[derive(Clone, Copy)]
+pub struct LinuxSystemInterface {
+    header: NonNull<Header>,
+}
+
+unsafe impl Send for LinuxSystemInterface {}
+unsafe impl Sync for LinuxSystemInterface {}
+
+struct Header {
+    memfd: i32,
+    physical_size: usize,
+    next_physical: AtomicUsize,
+    arena_start: AtomicUsize,
+    arena_pages: AtomicUsize,
+    /// for each page in the arena, the index of the mapped frame or 0.
+    frame_table: AtomicPtr<AtomicU32>,
+    /// offsets and sizes of freed ranges, which are handed out again before growing.
+    /// Reserved like the frame table, with room for every 4KiB of the memfd.
+    freed_ranges: *mut (usize, usize),
+    /// number of entries in `freed_ranges`.
+    freed: Mutex<usize>,
+}
+
+impl LinuxSystemInterface {
+    /// Creates a memfd backing up to `physical_size` bytes of emulated physical memory.
+    /// Memory is only committed once it is touched.
+    pub fn new(physical_size: usize) -> Self {
+        assert!(physical_size.is_multiple_of(PAGE_SIZE));
+        let memfd_size = physical_size + PAGE_SIZE;
+        assert!(memfd_size / PAGE_SIZE <= u32::MAX as usize);
+        let memfd = create_memfd(memfd_size);
+        let direct_map = reserve_aligned(memfd_size, PAGE_SIZE, PROT_NONE);
+        unsafe {
+            mmap_fixed(
+                direct_map,
+                memfd_size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                memfd,
+                0,
+            )
+        };
+        let header = NonNull::new(direct_map as *mut Header).unwrap();
+        let freed_ranges = reserve_aligned(
+            memfd_size / Size4KiB::SIZE as usize * size_of::<(usize, usize)>(),
+            1,
+            PROT_READ | PROT_WRITE,
+        );
+        unsafe {
+            header.write(Header {
+                memfd,
+                physical_size: memfd_size,
+                next_physical: AtomicUsize::new(PAGE_SIZE),
+                arena_start: AtomicUsize::new(0),
+                arena_pages: AtomicUsize::new(0),
+                frame_table: AtomicPtr::new(ptr::null_mut()),
+                freed_ranges: freed_ranges as *mut (usize, usize),
+                freed: Mutex::new(0),
+            })
+        };
+        LinuxSystemInterface { header }
+    }
+
+    /// Creates a copy of the current contents of the memfd.
+    /// Mappings of a memfd are shared across `fork`, so a child must switch to a snapshot taken before the fork.
+    pub fn snapshot(self) -> i32 {
+        let header = self.header();
+        let direct_map = self.header.as_ptr().addr();
+        let memfd = create_memfd(header.physical_size);
+        // copy only the parts of the memfd that were ever touched.
+        let mut offset = 0;
+        loop {
+            let data = unsafe { libc::lseek(header.memfd, offset, libc::SEEK_DATA) };
+            if data < 0 {
+                break;
+            }
+            let hole = unsafe { libc::lseek(header.memfd, data, libc::SEEK_HOLE) };
+            let mut copied = data;
+            while copied < hole {
+                let written = unsafe {
+                    libc::pwrite(
+                        memfd,
+                        ptr::with_exposed_provenance(direct_map + copied as usize),
+                        (hole - copied) as usize,
+                        copied,
+                    )
+                };
+                if written < 0 {
+                    panic!("pwrite failed: {:?}", std::io::Error::last_os_error());
+                }
+                copied += written as libc::off_t;
+            }
+            offset = hole;
+        }
+        memfd
+    }
+
+    /// Replaces all mappings of the memfd with mappings of a [snapshot](Self::snapshot).
+    ///
+    /// # Safety
+    /// No other thread may use the interface concurrently.
+    /// The snapshot must have been taken from this interface without any mappings changing since.
+    pub unsafe fn switch_to_snapshot(self, memfd: i32) {
+        let direct_map = self.header.as_ptr().addr();
+        let old_memfd = self.header().memfd;
+        mmap_fixed(
+            direct_map,
+            self.header().physical_size,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            memfd,
+            0,
+        );
+        let header = &mut *self.header.as_ptr();
+        header.memfd = memfd;
+        let arena_start = header.arena_start.load(Ordering::Relaxed);
+        let frame_table = header.frame_table.load(Ordering::Relaxed);
+        for i in 0..header.arena_pages.load(Ordering::Relaxed) {
+            let index = (*frame_table.add(i)).load(Ordering::Relaxed);
+            if index != 0 {
+                mmap_fixed(
+                    arena_start + i * PAGE_SIZE,
+                    PAGE_SIZE,
+                    PROT_READ | PROT_WRITE,
+                    MAP_SHARED,
+                    memfd,
+                    index as usize * PAGE_SIZE,
+                );
+            }
+        }
+        libc::close(old_memfd);
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { self.header.as_ref() }
+    }
+
+    fn frame_table_entry(&self, page: Page<HeapPageSize>) -> &AtomicU32 {
+        let header = self.header();
+        let addr = page.start_address().as_u64() as usize;
+        let start = header.arena_start.load(Ordering::Relaxed);
+        assert!(start != 0 && addr >= start);
+        let index = (addr - start) / PAGE_SIZE;
+        assert!(index < header.arena_pages.load(Ordering::Relaxed));
+        unsafe { &*header.frame_table.load(Ordering::Relaxed).add(index) }
+    }
+}
+
+unsafe impl SystemInterface for LinuxSystemInterface {
+    fn allocate_virtual(self, layout: Layout) -> VirtAddr {
+        assert!(layout.size().is_multiple_of(PAGE_SIZE));
+        let header = self.header();
+        let page_count = layout.size() / PAGE_SIZE;
+        let frame_table = reserve_aligned(
+            page_count * size_of::<AtomicU32>(),
+            1,
+            PROT_READ | PROT_WRITE,
+        );
+        let start = reserve_aligned_at(
+            arena_hint(layout.size()),
+            layout.size(),
+            layout.align().max(PAGE_SIZE),
+            PROT_NONE,
+        );
+        assert!(start + layout.size() <= lower_half_end(self.paging_levels()));
+        header
+            .frame_table
+            .store(frame_table as *mut AtomicU32, Ordering::Relaxed);
+        header.arena_pages.store(page_count, Ordering::Relaxed);
+        header
+            .arena_start
+            .compare_exchange(0, start, Ordering::Release, Ordering::Relaxed)
+            .expect("LinuxSystemInterface supports only a single virtual allocation");
+        VirtAddr::new(start as u64)
+    }
+
+    fn allocate_physical(self, layout: Layout) -> PhysAddr {
+        let header = self.header();
+        {
+            let mut freed = header.freed.lock().unwrap();
+            let ranges = unsafe { std::slice::from_raw_parts_mut(header.freed_ranges, *freed) };
+            if let Some(i) = ranges.iter().position(|&(offset, size)| {
+                size == layout.size() && offset.is_multiple_of(layout.align())
+            }) {
+                let offset = ranges[i].0;
+                ranges.swap(i, *freed - 1);
+                *freed -= 1;
+                return PhysAddr::new(offset as u64);
+            }
+        }
+        let mut start = 0;
+        header
+            .next_physical
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                start = next.next_multiple_of(layout.align());
+                Some(start + layout.size())
+            })
+            .unwrap();
+        assert!(
+            start + layout.size() <= header.physical_size,
+            "out of emulated physical memory"
+        );
+        PhysAddr::new(start as u64)
+    }
+
+    /// Sets the memory policy of the range to prefer `node`, which the kernel follows once its pages are touched.
+    fn allocate_physical_on(self, layout: Layout, node: usize) -> PhysAddr {
+        let addr = self.allocate_physical(layout);
+        let nodemask: u64 = 1 << node;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.vaddr(addr).as_u64(),
+                layout.size(),
+                libc::MPOL_PREFERRED,
+                &nodemask,
+                u64::BITS + 1,
+                0,
+            )
+        };
+        if result != 0 {
+            panic!("mbind failed: {:?}", std::io::Error::last_os_error());
+        }
+        addr
+    }
+
+    /// Reads the nodes the kernel considers possible, 1 if it has no NUMA support.
+    fn numa_node_count(self) -> usize {
+        possible_numa_nodes()
+    }
+
+    /// Faults in the first page of the frame, which is placed according to its memory policy.
+    fn frame_node(self, addr: PhysAddr) -> usize {
+        const MPOL_F_NODE: u64 = 1;
+        const MPOL_F_ADDR: u64 = 2;
+        let mut node: i32 = 0;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut node,
+                ptr::null_mut::<u64>(),
+                0,
+                self.vaddr(addr).as_u64(),
+                MPOL_F_NODE | MPOL_F_ADDR,
+            )
+        };
+        if result != 0 {
+            panic!(
+                "get_mempolicy failed: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+        node as usize
+    }
+
+    fn current_node(self) -> usize {
+        let mut node: u32 = 0;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_getcpu,
+                ptr::null_mut::<u32>(),
+                &mut node,
+                ptr::null_mut::<u8>(),
+            )
+        };
+        if result != 0 {
+            panic!("getcpu failed: {:?}", std::io::Error::last_os_error());
+        }
+        node as usize
+    }
+
+    /// Punches a hole into the memfd, so the memory is returned to the kernel.
+    /// The direct map keeps mapping the hole, reading from it commits a page again.
+    unsafe fn free_physical(self, addr: PhysAddr, layout: Layout) {
+        let header = self.header();
+        let offset = addr.as_u64() as usize;
+        let result = libc::fallocate(
+            header.memfd,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            layout.size() as libc::off_t,
+        );
+        if result != 0 {
+            panic!("fallocate failed: {:?}", std::io::Error::last_os_error());
+        }
+        let mut freed = header.freed.lock().unwrap();
+        header
+            .freed_ranges
+            .add(*freed)
+            .write((offset, layout.size()));
+        *freed += 1;
+    }
+
+    /// `mmap` and `munmap` already perform the necessary shootdowns.
+    fn global_tlb_flush(self) {}
+
+    fn flush_tlb_range(self, _range: Range<usize>) {}
+
+    fn has_range_flush(self) -> bool {
+        true
+    }
+
+    fn vaddr(self, addr: PhysAddr) -> VirtAddr {
+        VirtAddr::new(self.header.as_ptr().addr() as u64 + addr.as_u64())
+    }
+
+    fn paddr(self, addr: VirtAddr) -> PhysAddr {
+        PhysAddr::new(addr.as_u64() - self.header.as_ptr().addr() as u64)
+    }
+
+    /// The kernel maps above 128TiB only with five-level paging, and only if the hint lies there.
+    fn paging_levels(self) -> u32 {
+        static LEVELS: AtomicU32 = AtomicU32::new(0);
+        let mut levels = LEVELS.load(Ordering::Relaxed);
+        if levels == 0 {
+            let probe = reserve_aligned_at(lower_half_end(4), PAGE_SIZE, PAGE_SIZE, PROT_NONE);
+            unsafe { libc::munmap(ptr::with_exposed_provenance_mut(probe), PAGE_SIZE) };
+            levels = if probe >= lower_half_end(4) { 5 } else { 4 };
+            LEVELS.store(levels, Ordering::Relaxed);
+        }
+        levels
+    }
+
+    /// The kernel manages the page tables of the process.
+    unsafe fn prepare_page_table(self, _range: PageRangeInclusive<Size2MiB>) {}
+
+    unsafe fn map(self, page: Page<HeapPageSize>, frame: PhysFrame<HeapPageSize>) {
+        debug!("mapping {page:?} to {frame:?}");
+        let offset = frame.start_address().as_u64() as usize;
+        let old = self
+            .frame_table_entry(page)
+            .swap((offset / PAGE_SIZE) as u32, Ordering::Relaxed);
+        debug_assert!(old == 0);
+        mmap_fixed(
+            page.start_address().as_u64() as usize,
+            PAGE_SIZE,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            self.header().memfd,
+            offset,
+        );
+    }
+
+    unsafe fn unmap(self, page: Page<HeapPageSize>) -> PhysFrame<HeapPageSize> {
+        let index = self.frame_table_entry(page).swap(0, Ordering::Relaxed);
+        debug_assert!(index != 0);
+        // Keep the range reserved instead of calling munmap, so no other mapping can be placed there.
+        mmap_fixed(
+            page.start_address().as_u64() as usize,
+            PAGE_SIZE,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+            0,
+        );
+        let frame =
+            PhysFrame::from_start_address(PhysAddr::new((index as usize * PAGE_SIZE) as u64))
+                .unwrap();
+        debug!("unmapped {page:?}, was {frame:?}");
+        frame
+    }
+
+    /// Frames taken from the global pool in order are often consecutive, each run of them is mapped at once.
+    unsafe fn map_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        mut frames: impl FnMut() -> PhysFrame<HeapPageSize>,
+    ) {
+        debug!("mapping {count} pages from {start:?}");
+        let start = start.start_address().as_u64() as usize;
+        let map_run = |first: usize, end: usize, offset: usize| {
+            mmap_fixed(
+                start + first * PAGE_SIZE,
+                (end - first) * PAGE_SIZE,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                self.header().memfd,
+                offset,
+            )
+        };
+        let mut run_start = 0;
+        let mut run_offset = 0;
+        for i in 0..count {
+            let offset = frames().start_address().as_u64() as usize;
+            let old = self
+                .frame_table_entry(page_from_addr(vaddr_unchecked(start + i * PAGE_SIZE)))
+                .swap((offset / PAGE_SIZE) as u32, Ordering::Relaxed);
+            debug_assert!(old == 0);
+            if i == 0 {
+                run_offset = offset;
+            } else if offset != run_offset + (i - run_start) * PAGE_SIZE {
+                map_run(run_start, i, run_offset);
+                run_start = i;
+                run_offset = offset;
+            }
+        }
+        if count > 0 {
+            map_run(run_start, count, run_offset);
+        }
+    }
+
+    unsafe fn unmap_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        mut release: impl FnMut(PhysFrame<HeapPageSize>),
+    ) {
+        let start = start.start_address().as_u64() as usize;
+        mmap_fixed(
+            start,
+            count * PAGE_SIZE,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+            0,
+        );
+        for i in 0..count {
+            let index = self
+                .frame_table_entry(page_from_addr(vaddr_unchecked(start + i * PAGE_SIZE)))
+                .swap(0, Ordering::Relaxed);
+            debug_assert!(index != 0);
+            release(
+                PhysFrame::from_start_address(PhysAddr::new((index as usize * PAGE_SIZE) as u64))
+                    .unwrap(),
+            );
+        }
+        debug!("unmapped {count} pages from {start:x}");
+    }
+
+    /// Maps the memfd range of the frame at once and records each of its pages in the frame table.
+    unsafe fn map_giant(
+        self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        debug!("mapping {page:?} to {frame:?}");
+        let start = page.start_address().as_u64() as usize;
+        let offset = frame.start_address().as_u64() as usize;
+        for i in (0..Size1GiB::SIZE as usize).step_by(PAGE_SIZE) {
+            let entry = self.frame_table_entry(page_from_addr(vaddr_unchecked(start + i)));
+            let old = entry.swap(((offset + i) / PAGE_SIZE) as u32, Ordering::Relaxed);
+            debug_assert!(old == 0);
+        }
+        mmap_fixed(
+            start,
+            Size1GiB::SIZE as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            self.header().memfd,
+            offset,
+        );
+        None
+    }
+
+    unsafe fn unmap_giant(
+        self,
+        page: Page<Size1GiB>,
+        _table: Option<PhysFrame<Size4KiB>>,
+    ) -> PhysFrame<Size1GiB> {
+        let start = page.start_address().as_u64() as usize;
+        let mut first_index = 0;
+        for i in (0..Size1GiB::SIZE as usize).step_by(PAGE_SIZE) {
+            let entry = self.frame_table_entry(page_from_addr(vaddr_unchecked(start + i)));
+            let index = entry.swap(0, Ordering::Relaxed);
+            if i == 0 {
+                first_index = index;
+            }
+        }
+        mmap_fixed(
+            start,
+            Size1GiB::SIZE as usize,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+            0,
+        );
+        let frame =
+            PhysFrame::from_start_address(PhysAddr::new((first_index as usize * PAGE_SIZE) as u64))
+                .unwrap();
+        debug!("unmapped {page:?}, was {frame:?}");
+        frame
+    }
+
+    fn allocator(self) -> Self::Alloc {
+        System
+    }
+
+    type Alloc = System;
+}
+
+fn create_memfd(size: usize) -> i32 {
+    let memfd = unsafe { libc::memfd_create(c"virtual_alloc".as_ptr(), MFD_CLOEXEC) };
+    if memfd < 0 {
+        panic!("memfd_create failed: {:?}", std::io::Error::last_os_error());
+    }
+    if unsafe { libc::ftruncate(memfd, size as libc::off_t) } != 0 {
+        panic!("ftruncate failed: {:?}", std::io::Error::last_os_error());
+    }
+    memfd
+}
+
+/// Parses `/sys/devices/system/node/possible`, a range like `0-3`.
+/// Reads into a buffer on the stack, as this may run inside the process allocator.
+fn possible_numa_nodes() -> usize {
+    let fd = unsafe {
+        libc::open(
+            c"/sys/devices/system/node/possible".as_ptr(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return 1;
+    }
+    let mut buf = [0u8; 32];
+    let len = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    unsafe { libc::close(fd) };
+    let Ok(len) = usize::try_from(len) else {
+        return 1;
+    };
+    let last = buf[..len]
+        .trim_ascii()
+        .rsplit(|&c| c == b'-' || c == b',')
+        .next()
+        .and_then(|n| std::str::from_utf8(n).ok()?.parse::<usize>().ok());
+    last.map_or(1, |n| n + 1)
+}
+
+/// Reserves an aligned range of address space without committing memory.
+fn reserve_aligned(size: usize, align: usize, prot: i32) -> usize {
+    reserve_aligned_at(0, size, align, prot)
+}
+
+/// Like [reserve_aligned], but passes `hint` to the kernel as the preferred address.
+fn reserve_aligned_at(hint: usize, size: usize, align: usize, prot: i32) -> usize {
+    let p = unsafe {
+        libc::mmap(
+            ptr::with_exposed_provenance_mut(hint),
+            size + align,
+            prot,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if p == libc::MAP_FAILED {
+        panic!("mmap failed: {:?}", std::io::Error::last_os_error());
+    }
+    let start = p.addr().next_multiple_of(align);
+    // give back the parts only reserved for alignment.
+    unsafe {
+        libc::munmap(p, start - p.addr());
+        libc::munmap(
+            ptr::with_exposed_provenance_mut(start + size),
+            p.addr() + align - start,
+        );
+    }
+    start
+}
+
+unsafe fn mmap_fixed(addr: usize, size: usize, prot: i32, flags: i32, fd: i32, offset: usize) {
+    let p = libc::mmap(
+        ptr::with_exposed_provenance_mut(addr),
+        size,
+        prot,
+        flags | MAP_FIXED,
+        fd,
+        offset as libc::off_t,
+    );
+    if p == libc::MAP_FAILED {
+        panic!("mmap failed: {:?}", std::io::Error::last_os_error());
+    }
+}
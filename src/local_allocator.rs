@@ -0,0 +1,101 @@
+use crate::myalloc::{placed_size, GlobalData, LocalData};
+use crate::{SystemInterface, TestAlloc};
+use std::alloc::{AllocError, Allocator, Layout};
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// An [Allocator] placing allocations in the heap of a specific [LocalData].
+///
+/// Memory may be deallocated through any handle sharing the same [GlobalData].
+pub struct LocalAllocator<'a, S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> {
+    local: &'a RefCell<LocalData<S, G>>,
+}
+
+impl<'a, S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> LocalAllocator<'a, S, G> {
+    pub fn new(local: &'a RefCell<LocalData<S, G>>) -> Self {
+        LocalAllocator { local }
+    }
+
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self
+            .local
+            .borrow_mut()
+            .realloc(ptr, placed_size(old_layout), new_layout)
+            .ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+}
+
+impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> Clone
+    for LocalAllocator<'_, S, G>
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> Copy
+    for LocalAllocator<'_, S, G>
+{
+}
+
+unsafe impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> Allocator
+    for LocalAllocator<'_, S, G>
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = unsafe { self.local.borrow_mut().alloc(layout) }.ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        // frames are recycled without being cleared.
+        unsafe { ptr.cast::<u8>().as_ptr().write_bytes(0, layout.size()) };
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.local.borrow_mut().dealloc(ptr, placed_size(layout));
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.grow(ptr, old_layout, new_layout)?;
+        new_ptr
+            .cast::<u8>()
+            .as_ptr()
+            .add(old_layout.size())
+            .write_bytes(0, new_layout.size() - old_layout.size());
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,114 @@
+//! The malloc family on top of a [VirtualGlobalAlloc].
+//!
+//! Memory is freed without passing its size, which the heap records with the `sizeless_free` feature.
+
+use crate::{GlobalData, SystemInterface, VirtualGlobalAlloc};
+use std::alloc::{GlobalAlloc, Layout};
+use std::ptr;
+
+/// The alignment guaranteed by `malloc`.
+pub const MIN_ALIGN: usize = 16;
+
+/// # Safety
+/// align must be a power of two.
+pub unsafe fn malloc_aligned<S: SystemInterface + 'static>(
+    a: &VirtualGlobalAlloc<S>,
+    size: usize,
+    align: usize,
+) -> *mut u8
+where
+    GlobalData<S>: Sync,
+{
+    // zero sized allocations have no size recorded.
+    match Layout::from_size_align(size.max(1), align.max(MIN_ALIGN)) {
+        Ok(layout) => a.alloc(layout),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+pub unsafe fn malloc<S: SystemInterface + 'static>(
+    a: &VirtualGlobalAlloc<S>,
+    size: usize,
+) -> *mut u8
+where
+    GlobalData<S>: Sync,
+{
+    malloc_aligned(a, size, MIN_ALIGN)
+}
+
+pub unsafe fn calloc<S: SystemInterface + 'static>(
+    a: &VirtualGlobalAlloc<S>,
+    count: usize,
+    size: usize,
+) -> *mut u8
+where
+    GlobalData<S>: Sync,
+{
+    let Some(size) = count.checked_mul(size) else {
+        return ptr::null_mut();
+    };
+    let ptr = malloc(a, size);
+    if !ptr.is_null() {
+        // frames are recycled without being cleared.
+        ptr.write_bytes(0, size);
+    }
+    ptr
+}
+
+/// # Safety
+/// ptr must be null or returned by one of the functions in this module using the same allocator.
+pub unsafe fn free<S: SystemInterface + 'static>(a: &VirtualGlobalAlloc<S>, ptr: *mut u8)
+where
+    GlobalData<S>: Sync,
+{
+    if ptr.is_null() {
+        return;
+    }
+    let size = a.allocation_size(ptr);
+    a.dealloc(ptr, Layout::from_size_align_unchecked(size, MIN_ALIGN));
+}
+
+/// Like C `realloc`, a size of 0 frees the allocation and returns null.
+///
+/// # Safety
+/// See [free].
+pub unsafe fn realloc<S: SystemInterface + 'static>(
+    a: &VirtualGlobalAlloc<S>,
+    ptr: *mut u8,
+    new_size: usize,
+) -> *mut u8
+where
+    GlobalData<S>: Sync,
+{
+    if ptr.is_null() {
+        return malloc(a, new_size);
+    }
+    if new_size == 0 {
+        free(a, ptr);
+        return ptr::null_mut();
+    }
+    if Layout::from_size_align(new_size, MIN_ALIGN).is_err() {
+        return ptr::null_mut();
+    }
+    let size = a.allocation_size(ptr);
+    a.realloc(
+        ptr,
+        Layout::from_size_align_unchecked(size, MIN_ALIGN),
+        new_size,
+    )
+}
+
+/// # Safety
+/// See [free].
+pub unsafe fn usable_size<S: SystemInterface + 'static>(
+    a: &VirtualGlobalAlloc<S>,
+    ptr: *mut u8,
+) -> usize
+where
+    GlobalData<S>: Sync,
+{
+    if ptr.is_null() {
+        return 0;
+    }
+    a.allocation_size(ptr)
+}
@@ -1,72 +1,150 @@
-use crate::frame_list::{FrameList, FrameList2M};
-use crate::myalloc::large_allocator::{alloc_large, dealloc_large};
+use crate::frame_list::{FrameList, HeapFrameList, HeapFramePool};
+use crate::myalloc::giant_pages::{GiantPages, GIANT_PAGE_SIZE};
+use crate::myalloc::large_allocator::{
+    alloc_large, dealloc_large, large_alloc_level, realloc_large,
+};
 use crate::myalloc::medium_allocator::MediumAllocator;
 use crate::myalloc::quantum_storage::QuantumStorage;
+#[cfg(feature = "size_classes")]
+use crate::myalloc::size_class_allocator::SizeClassAllocator as SmallAllocator;
+#[cfg(not(feature = "size_classes"))]
 use crate::myalloc::small_allocator::SmallAllocator;
 use crate::quantum_address::QuantumAddress;
-use crate::util::{PAGE_SIZE, VIRTUAL_QUANTUM_SIZE};
+use crate::util::{
+    align_up_const, lower_half_end, page_from_addr, vaddr_unchecked, PAGE_SIZE,
+    VIRTUAL_QUANTUM_SIZE,
+};
 use crate::{SystemInterface, TestAlloc};
+pub use heap_config::{GlobalDataBuilder, HeapConfig};
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
 use std::alloc::Layout;
 use std::ops::Deref;
 use std::ptr::NonNull;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 use x86_64::structures::paging::page::PageRangeInclusive;
-use x86_64::structures::paging::{Page, PageSize, PhysFrame, Size2MiB};
+use x86_64::structures::paging::{PageSize, Size2MiB};
 
+mod giant_pages;
+mod heap_config;
 mod large_allocator;
 mod medium_allocator;
 mod quantum_storage;
+#[cfg(feature = "size_classes")]
+mod size_class_allocator;
+#[cfg(not(feature = "size_classes"))]
 mod small_allocator;
+#[cfg(test)]
+mod tests;
 
 pub struct GlobalData<S: SystemInterface> {
-    available_frames: Mutex<Vec<PhysFrame<Size2MiB>, S::Alloc>>,
+    available_frames: HeapFramePool<S>,
     quantum_storage: QuantumStorage<S>,
+    giant_pages: GiantPages<S>,
+    /// bumped to make handles return their cached frames, see [release_memory](Self::release_memory).
+    cache_release_epoch: AtomicUsize,
+    /// mapped size of the large allocation starting at each quantum, 0 if there is none.
+    #[cfg(feature = "sizeless_free")]
+    large_sizes: Box<[AtomicUsize], S::Alloc>,
+    config: HeapConfig,
     sys: S,
 }
 
 impl<S: SystemInterface> GlobalData<S> {
+    /// Creates a heap with the default [HeapConfig].
     pub fn new(sys: S, physical_size: usize, virt_size: usize) -> Self {
-        assert!(virt_size.is_multiple_of(VIRTUAL_QUANTUM_SIZE));
-        assert!(physical_size.is_multiple_of(Size2MiB::SIZE as usize));
-        let frame_count = physical_size / Size2MiB::SIZE as usize;
-        let page_count = virt_size / Size2MiB::SIZE as usize;
-        let virt_start = Page::from_start_address(
-            sys.allocate_virtual(Layout::from_size_align(virt_size, VIRTUAL_QUANTUM_SIZE).unwrap()),
-        )
-        .unwrap();
-        let virt_end = virt_start + page_count as u64;
-
-        assert!(virt_size <= 1 << 46);
-
-        let mut frames = Vec::with_capacity_in(frame_count, sys.allocator());
-        for _ in 0..frame_count {
-            frames.push(
-                PhysFrame::<Size2MiB>::from_start_address(
-                    sys.allocate_physical(
-                        Layout::from_size_align(Size2MiB::SIZE as usize, Size2MiB::SIZE as usize)
-                            .unwrap(),
-                    ),
-                )
-                .unwrap(),
-            )
+        Self::builder(sys, physical_size, virt_size).build()
+    }
+
+    pub fn builder(sys: S, physical_size: usize, virt_size: usize) -> GlobalDataBuilder<S> {
+        GlobalDataBuilder::new(sys, physical_size, virt_size)
+    }
+
+    pub fn config(&self) -> &HeapConfig {
+        &self.config
+    }
+
+    /// Returns frames from the global pool to the system until at most `target` bytes remain in it.
+    /// Handles return their cached frames to the pool the next time they free memory, so a later call may release more.
+    /// Returns the number of bytes released.
+    pub fn release_memory(&self, target: usize) -> usize {
+        self.cache_release_epoch.fetch_add(1, Relaxed);
+        self.available_frames.release(target / PAGE_SIZE) * PAGE_SIZE
+    }
+
+    /// A size the allocation at `ptr` may be freed or resized with, at least the size it was allocated with.
+    ///
+    /// # Safety
+    /// ptr must be a live allocation of this heap with a nonzero size.
+    #[cfg(feature = "sizeless_free")]
+    pub unsafe fn allocation_size(&self, ptr: NonNull<u8>) -> usize {
+        let index =
+            ptr.addr().get().wrapping_sub(self.quantum_storage.base()) / VIRTUAL_QUANTUM_SIZE;
+        match self.large_sizes.get(index).map(|size| size.load(Relaxed)) {
+            // small objects live in the direct map, outside the arena.
+            None => size_class_allocator::allocation_size(ptr).min(self.config.max_small_size),
+            Some(0) => medium_allocator::allocation_size(ptr),
+            Some(size) => size,
         }
+    }
+
+    /// Remembers the mapped size of the large allocation at `start`, 0 once it is freed.
+    #[inline]
+    fn set_large_size(&self, start: usize, size: usize) {
+        #[cfg(feature = "sizeless_free")]
+        self.large_sizes[(start - self.quantum_storage.base()) / VIRTUAL_QUANTUM_SIZE]
+            .store(size, Relaxed);
+        #[cfg(not(feature = "sizeless_free"))]
+        let _ = (start, size);
+    }
+
+    fn with_config(sys: S, physical_size: usize, virt_size: usize, config: HeapConfig) -> Self {
+        assert!(virt_size.is_multiple_of(VIRTUAL_QUANTUM_SIZE));
+        assert!(physical_size.is_multiple_of(PAGE_SIZE));
+        let frame_count = physical_size / PAGE_SIZE;
+        // half of the lower half, so the aligned arena always fits.
+        assert!(virt_size <= lower_half_end(sys.paging_levels()) / 2);
+        // align the arena to the largest buddy block, so that every block is aligned to its size.
+        let virt_align = VIRTUAL_QUANTUM_SIZE
+            << QuantumStorage::<S>::top_level(virt_size / VIRTUAL_QUANTUM_SIZE, &config);
+        let virt_start = sys
+            .allocate_virtual(Layout::from_size_align(virt_size, virt_align).unwrap())
+            .as_u64() as usize;
+        assert!(virt_start.is_multiple_of(Size2MiB::SIZE as usize));
+        let virt_end = virt_start + virt_size;
+        assert!(config.giant_frames == 0 || virt_size >= GIANT_PAGE_SIZE);
+
+        let max_physical_size = config.max_physical_size.unwrap_or(physical_size);
+        assert!(max_physical_size >= physical_size);
+        let frames = HeapFramePool::new(
+            sys,
+            frame_count,
+            max_physical_size / PAGE_SIZE,
+            config.frame_growth_size,
+            config.node_fallback,
+        );
         unsafe {
             sys.prepare_page_table(PageRangeInclusive {
-                start: virt_start,
-                end: virt_end - 1,
+                start: page_from_addr(vaddr_unchecked(virt_start)),
+                end: page_from_addr(vaddr_unchecked(virt_end - Size2MiB::SIZE as usize)),
             })
         };
 
         GlobalData {
             quantum_storage: {
-                let start =
-                    QuantumAddress::from_start(virt_start.start_address().as_u64() as usize);
-                let end = QuantumAddress::from_start(virt_end.start_address().as_u64() as usize);
-                QuantumStorage::from_range(sys, start..end)
+                let start = QuantumAddress::from_start(virt_start);
+                let end = QuantumAddress::from_start(virt_end);
+                QuantumStorage::from_range(sys, start..end, &config)
+            },
+            giant_pages: GiantPages::new(sys, virt_start..virt_end, config.giant_frames),
+            available_frames: frames,
+            cache_release_epoch: AtomicUsize::new(0),
+            #[cfg(feature = "sizeless_free")]
+            large_sizes: unsafe {
+                Box::new_zeroed_slice_in(virt_size / VIRTUAL_QUANTUM_SIZE, sys.allocator())
+                    .assume_init()
             },
-            available_frames: Mutex::new(frames),
+            config,
             sys,
         }
     }
@@ -81,25 +159,73 @@ pub struct LocalData<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send
 struct LocalCommon<S: SystemInterface, G: Deref<Target = GlobalData<S>>> {
     global: G,
     rng: SmallRng,
-    available_frames: FrameList2M<S>,
+    available_frames: HeapFrameList<S>,
+    /// the last [GlobalData::cache_release_epoch] seen.
+    cache_release_epoch: usize,
+    /// the quantum shard tried first.
+    quantum_shard: usize,
 }
 
-const MAX_MEDIUM_SIZE: usize = (VIRTUAL_QUANTUM_SIZE * PAGE_SIZE).isqrt();
-const MAX_SMALL_SIZE: usize = PAGE_SIZE / 16;
+/// The part of the allocator responsible for allocations of a given size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Tier {
+    Zero,
+    Small,
+    Medium,
+    Large,
+}
+
+/// The size an allocation with `layout` takes up, which it must be freed and resized with.
+/// Frames in the direct map are not aligned beyond a page,
+/// so nonzero allocations aligned to more than that take up their whole alignment and are placed in a higher tier.
+#[inline]
+pub fn placed_size(layout: Layout) -> usize {
+    if std::hint::unlikely(layout.align() > PAGE_SIZE && layout.size() != 0) {
+        layout.size().max(layout.align())
+    } else {
+        layout.size()
+    }
+}
+
+impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> LocalCommon<S, G> {
+    /// Returns cached frames to the global pool once there are more than the configured limit,
+    /// or all of them if [GlobalData::release_memory] was called since the last time.
+    fn release_extra_frames(&mut self) {
+        let epoch = self.global.cache_release_epoch.load(Relaxed);
+        if std::hint::unlikely(epoch != self.cache_release_epoch) {
+            self.cache_release_epoch = epoch;
+            self.available_frames
+                .release_all_to_vec(&self.global.available_frames);
+            return;
+        }
+        self.available_frames.release_extra_to_vec(
+            &self.global.available_frames,
+            self.global.config.frame_cache_limit,
+        );
+    }
+}
 
 unsafe impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> TestAlloc
     for LocalData<S, G>
 {
     #[inline]
     unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
-        if std::hint::likely(layout.size() <= MAX_SMALL_SIZE) {
+        let config = &self.common.global.config;
+        let layout = Layout::from_size_align(placed_size(layout), layout.align()).ok()?;
+        if std::hint::likely(layout.size() <= config.max_small_size) {
             if std::hint::likely(layout.size() != 0) {
                 self.small.alloc(&mut self.common, layout)
             } else {
                 Some(NonNull::new_unchecked(layout.dangling().as_ptr()))
             }
-        } else if std::hint::likely(layout.size() < MAX_MEDIUM_SIZE) {
-            self.medium.alloc(&mut self.common, layout)
+        } else if std::hint::likely(layout.size() < config.max_medium_size) {
+            let ptr = self.medium.alloc(&mut self.common, layout);
+            #[cfg(feature = "sizeless_free")]
+            if let Some(ptr) = ptr {
+                medium_allocator::record_size(ptr, layout.size());
+            }
+            ptr
         } else {
             alloc_large(&mut self.common, layout)
         }
@@ -107,12 +233,13 @@ unsafe impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> TestAll
 
     #[inline]
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, size: usize) {
-        if std::hint::likely(size <= MAX_SMALL_SIZE) {
+        let config = &self.common.global.config;
+        if std::hint::likely(size <= config.max_small_size) {
             if std::hint::likely(size != 0) {
-                SmallAllocator::dealloc(&mut self.common, ptr.as_ptr());
+                self.small.dealloc(&mut self.common, ptr.as_ptr());
             }
-        } else if std::hint::likely(size < MAX_MEDIUM_SIZE) {
-            MediumAllocator::dealloc(&mut self.common, ptr.as_ptr(), size);
+        } else if std::hint::likely(size < config.max_medium_size) {
+            self.medium.dealloc(&mut self.common, ptr.as_ptr(), size);
         } else {
             dealloc_large(&mut self.common, ptr.as_ptr(), size);
         }
@@ -124,6 +251,8 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> LocalData<S, G
         LocalData {
             common: LocalCommon {
                 available_frames: FrameList::new(global.sys),
+                cache_release_epoch: global.cache_release_epoch.load(Relaxed),
+                quantum_shard: global.quantum_storage.home_shard(),
                 global,
                 rng: SmallRng::seed_from_u64(seed),
             },
@@ -131,10 +260,68 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> LocalData<S, G
             medium: MediumAllocator::new(),
         }
     }
+
+    /// Resizes an allocation, keeping its contents up to the smaller of the two sizes.
+    /// Large allocations are moved by remapping their frames instead of copying.
+    /// On failure, the original allocation is left untouched.
+    ///
+    /// # Safety
+    /// ptr must have been allocated from this allocator, old_size must be the [placed_size] of its layout.
+    pub unsafe fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_size: usize,
+        new_layout: Layout,
+    ) -> Option<NonNull<u8>> {
+        let new_size = placed_size(new_layout);
+        let config = &self.common.global.config;
+        if ptr.addr().get().is_multiple_of(new_layout.align())
+            && resizes_in_place(config, ptr, old_size, new_size)
+        {
+            return Some(ptr);
+        }
+        if old_size >= config.max_medium_size
+            && new_size >= config.max_medium_size
+            && new_layout.align() <= PAGE_SIZE
+        {
+            return realloc_large(&mut self.common, ptr, old_size, new_size);
+        }
+        let new_ptr = self.alloc(new_layout)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), old_size.min(new_size));
+        self.dealloc(ptr, old_size);
+        Some(new_ptr)
+    }
 }
+
+/// Returns true if the allocation of `old_size` bytes at `ptr` may be treated as an allocation of `new_size` bytes without moving it.
+/// Allocations are placed below previously allocated objects, so small and medium allocations can only shrink in place.
+fn resizes_in_place(
+    config: &HeapConfig,
+    ptr: NonNull<u8>,
+    old_size: usize,
+    new_size: usize,
+) -> bool {
+    let addr = ptr.addr().get();
+    if old_size <= config.max_small_size {
+        0 < new_size && new_size <= old_size
+    } else if old_size < config.max_medium_size {
+        config.max_small_size < new_size
+            && new_size <= old_size
+            && align_up_const::<PAGE_SIZE>(addr + new_size)
+                == align_up_const::<PAGE_SIZE>(addr + old_size)
+    } else {
+        config.max_medium_size <= new_size
+            && large_alloc_level(new_size) == large_alloc_level(old_size)
+            && new_size.next_multiple_of(PAGE_SIZE) == old_size.next_multiple_of(PAGE_SIZE)
+    }
+}
+
 impl<S: SystemInterface, G: Deref<Target = GlobalData<S>> + Send> Drop for LocalData<S, G> {
     fn drop(&mut self) {
         self.small.deinit(&mut self.common);
         self.medium.deinit(&mut self.common);
+        self.common
+            .available_frames
+            .release_all_to_vec(&self.common.global.available_frames);
     }
 }
@@ -0,0 +1,147 @@
+use crate::{
+    util::{page_from_addr, vaddr_unchecked},
+    SystemInterface,
+};
+use std::{
+    alloc::Layout,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+};
+use x86_64::{
+    structures::paging::{Page, PageSize, PhysFrame, Size1GiB},
+    PhysAddr,
+};
+
+pub const GIANT_PAGE_SIZE: usize = Size1GiB::SIZE as usize;
+
+/// A pool of physically contiguous 1GiB frames and the parts of the arena they are mapped to.
+/// Giant pages are only ever mapped and unmapped as a whole.
+pub struct GiantPages<S: SystemInterface> {
+    base: usize,
+    /// the first pooled frame, each holds the address of the next in its first word.
+    /// The pool lives in the frames, as this may run inside the process allocator.
+    free: Mutex<Option<PhysFrame<Size1GiB>>>,
+    /// for each 1GiB of the arena, 0 if it is not mapped by a giant page.
+    /// Otherwise, the address of the level 2 table the mapping replaced, or 1 if there was none.
+    mappings: Vec<AtomicUsize, S::Alloc>,
+}
+
+impl<S: SystemInterface> GiantPages<S> {
+    /// Claims `frame_count` frames from the system for the 1GiB aligned range.
+    pub fn new(sys: S, range: Range<usize>, frame_count: usize) -> Self {
+        let mut pages = GiantPages {
+            base: range.start,
+            free: Mutex::new(None),
+            mappings: Vec::new_in(sys.allocator()),
+        };
+        if frame_count == 0 {
+            return pages;
+        }
+        assert!(range.start.is_multiple_of(GIANT_PAGE_SIZE));
+        let layout = Layout::from_size_align(GIANT_PAGE_SIZE, GIANT_PAGE_SIZE).unwrap();
+        for _ in 0..frame_count {
+            pages.push(
+                sys,
+                PhysFrame::from_start_address(sys.allocate_physical(layout)).unwrap(),
+            );
+        }
+        pages
+            .mappings
+            .resize_with((range.end - range.start) / GIANT_PAGE_SIZE, || {
+                AtomicUsize::new(0)
+            });
+        pages
+    }
+
+    fn push(&self, sys: S, frame: PhysFrame<Size1GiB>) {
+        let mut free = self.free.lock().unwrap();
+        let next = free.map_or(0, |f| f.start_address().as_u64());
+        unsafe {
+            sys.vaddr(frame.start_address())
+                .as_mut_ptr::<u64>()
+                .write(next)
+        };
+        *free = Some(frame);
+    }
+
+    fn pop(&self, sys: S) -> Option<PhysFrame<Size1GiB>> {
+        let mut free = self.free.lock().unwrap();
+        let frame = (*free)?;
+        let next = unsafe { sys.vaddr(frame.start_address()).as_ptr::<u64>().read() };
+        *free = (next != 0).then(|| PhysFrame::from_start_address(PhysAddr::new(next)).unwrap());
+        Some(frame)
+    }
+
+    #[inline]
+    fn slot(&self, addr: usize) -> Option<&AtomicUsize> {
+        if self.mappings.is_empty() {
+            return None;
+        }
+        self.mappings.get((addr - self.base) / GIANT_PAGE_SIZE)
+    }
+
+    /// Maps a pooled frame to the 1GiB at `addr`, which must not map any pages.
+    /// Returns false if the pool is empty.
+    pub fn try_map(&self, sys: S, addr: usize) -> bool {
+        let Some(slot) = self.slot(addr) else {
+            return false;
+        };
+        let Some(frame) = self.pop(sys) else {
+            return false;
+        };
+        let table = unsafe { sys.map_giant(giant_page(addr), frame) };
+        slot.store(
+            table.map_or(1, |t| t.start_address().as_u64() as usize),
+            Relaxed,
+        );
+        true
+    }
+
+    /// Returns false if the 1GiB at `addr` is not mapped by a giant page.
+    /// Otherwise, it is unmapped and its frame returned to the pool.
+    pub fn unmap(&self, sys: S, addr: usize) -> bool {
+        match self.take(sys, addr) {
+            Some(frame) => {
+                self.push(sys, frame);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the giant page at `from`, if any, to `to`, which must not map any pages.
+    pub fn remap(&self, sys: S, from: usize, to: usize) -> bool {
+        let Some(frame) = self.take(sys, from) else {
+            return false;
+        };
+        let table = unsafe { sys.map_giant(giant_page(to), frame) };
+        self.slot(to).unwrap().store(
+            table.map_or(1, |t| t.start_address().as_u64() as usize),
+            Relaxed,
+        );
+        true
+    }
+
+    /// True if the 1GiB containing `addr` is mapped by a giant page.
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.slot(addr).is_some_and(|slot| slot.load(Relaxed) != 0)
+    }
+
+    fn take(&self, sys: S, addr: usize) -> Option<PhysFrame<Size1GiB>> {
+        let slot = self.slot(addr)?;
+        let mapping = slot.swap(0, Relaxed);
+        if mapping == 0 {
+            return None;
+        }
+        let table = (mapping != 1)
+            .then(|| PhysFrame::from_start_address(PhysAddr::new(mapping as u64)).unwrap());
+        Some(unsafe { sys.unmap_giant(giant_page(addr), table) })
+    }
+}
+
+fn giant_page(addr: usize) -> Page<Size1GiB> {
+    unsafe { page_from_addr(vaddr_unchecked(addr)) }
+}
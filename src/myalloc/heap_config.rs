@@ -0,0 +1,236 @@
+use crate::{
+    frame_list::HeapFrameList,
+    myalloc::{GlobalData, Tier},
+    util::{PAGE_SIZE, VIRTUAL_QUANTUM_SIZE},
+    SystemInterface,
+};
+
+/// Policy parameters of a heap, fixed when its [GlobalData] is created.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapConfig {
+    /// largest size served by the small allocator.
+    pub max_small_size: usize,
+    /// sizes from this on are served by mapping whole quanta.
+    pub max_medium_size: usize,
+    /// once a handle caches more frames than this, all but one are returned to the global pool.
+    pub frame_cache_limit: usize,
+    /// number of size classes a handle keeps frames to allocate from, each holding at least one, possibly empty, frame.
+    /// Opening another class gives up the frames of one of them, which are released once their objects are freed.
+    /// Ignored without the `size_classes` feature.
+    pub size_class_limit: usize,
+    /// number of frames the medium allocator takes from the global pool at once.
+    pub frame_refill_size: usize,
+    /// how often allocating a quantum recycles released quanta before giving up.
+    pub quantum_alloc_rounds: u32,
+    /// passed to the buddy allocator to bound the search for a free block.
+    pub quantum_search_budget: usize,
+    /// number of independently recycled partitions of the virtual arena, rounded so that they have a power-of-two size.
+    /// Handles allocate from their own shard first, but no allocation can be larger than a shard.
+    pub quantum_shards: usize,
+    /// released quanta moved per TLB flush, half the quanta in a shard if not set.
+    pub transfer_buffer_capacity: Option<usize>,
+    /// the frame pool grows up to this size once the physical size is used up, never if not set.
+    pub max_physical_size: Option<usize>,
+    /// number of frames claimed from the system whenever the frame pool grows.
+    pub frame_growth_size: usize,
+    /// batches of released quanta up to this size are flushed from the TLB one by one, larger ones globally.
+    /// Only applies if [SystemInterface::has_range_flush].
+    pub targeted_flush_limit: usize,
+    /// physically contiguous 1GiB frames claimed in addition to the physical size.
+    /// Large allocations map them wherever they cover an aligned GiB.
+    pub giant_frames: usize,
+    /// for each NUMA node, the other nodes to take frames from once its own run out, nearest first.
+    /// Nodes missing from a list are never used by it.
+    /// If not set, a node tries all others in ascending order after itself.
+    /// Ignored without the `numa` feature.
+    pub node_fallback: Option<&'static [&'static [usize]]>,
+}
+
+impl HeapConfig {
+    pub const DEFAULT: HeapConfig = HeapConfig {
+        max_small_size: PAGE_SIZE / 16,
+        max_medium_size: (VIRTUAL_QUANTUM_SIZE * PAGE_SIZE).isqrt(),
+        frame_cache_limit: 4,
+        size_class_limit: 8,
+        frame_refill_size: 4,
+        quantum_alloc_rounds: 32,
+        quantum_search_budget: 8 * 64 * 16,
+        quantum_shards: 1,
+        transfer_buffer_capacity: None,
+        max_physical_size: None,
+        frame_growth_size: 64,
+        targeted_flush_limit: 8,
+        giant_frames: 0,
+        node_fallback: None,
+    };
+
+    pub fn validate<S: SystemInterface>(&self) -> Result<(), &'static str> {
+        let frame_list_capacity = HeapFrameList::<S>::CAPACITY;
+        if self.max_small_size < 16 || self.max_small_size >= PAGE_SIZE / 2 {
+            return Err("max_small_size must be in 16..PAGE_SIZE / 2");
+        }
+        #[cfg(feature = "sizeless_free")]
+        if self.max_small_size < super::medium_allocator::SIZE_GRANULE {
+            return Err("max_small_size must be at least PAGE_SIZE / 16 to record medium sizes");
+        }
+        if self.max_medium_size <= self.max_small_size
+            || self.max_medium_size > VIRTUAL_QUANTUM_SIZE / 2
+        {
+            return Err("max_medium_size must be in max_small_size + 1..=VIRTUAL_QUANTUM_SIZE / 2");
+        }
+        // the frames for the pages of a medium object are taken into the frame list at once.
+        if self.max_medium_size / PAGE_SIZE + 2 >= frame_list_capacity {
+            return Err("max_medium_size must not span more pages than the frame list holds");
+        }
+        if self.frame_cache_limit == 0 || self.frame_cache_limit >= frame_list_capacity {
+            return Err("frame_cache_limit must be positive and below the frame list capacity");
+        }
+        if self.size_class_limit == 0 {
+            return Err("size_class_limit must be positive");
+        }
+        if self.frame_refill_size == 0 || self.frame_refill_size >= frame_list_capacity {
+            return Err("frame_refill_size must be positive and below the frame list capacity");
+        }
+        if self.quantum_alloc_rounds == 0 {
+            return Err("quantum_alloc_rounds must be positive");
+        }
+        if self
+            .max_physical_size
+            .is_some_and(|size| !size.is_multiple_of(PAGE_SIZE))
+        {
+            return Err("max_physical_size must be a multiple of PAGE_SIZE");
+        }
+        if self.frame_growth_size == 0 {
+            return Err("frame_growth_size must be positive");
+        }
+        if self.quantum_shards == 0 {
+            return Err("quantum_shards must be positive");
+        }
+        if self.transfer_buffer_capacity == Some(0) {
+            return Err("transfer_buffer_capacity must be positive");
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn tier(&self, size: usize) -> Tier {
+        if size == 0 {
+            Tier::Zero
+        } else if size <= self.max_small_size {
+            Tier::Small
+        } else if size < self.max_medium_size {
+            Tier::Medium
+        } else {
+            Tier::Large
+        }
+    }
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Created by [GlobalData::builder].
+pub struct GlobalDataBuilder<S: SystemInterface> {
+    sys: S,
+    physical_size: usize,
+    virt_size: usize,
+    config: HeapConfig,
+}
+
+impl<S: SystemInterface> GlobalDataBuilder<S> {
+    pub(super) fn new(sys: S, physical_size: usize, virt_size: usize) -> Self {
+        GlobalDataBuilder {
+            sys,
+            physical_size,
+            virt_size,
+            config: HeapConfig::DEFAULT,
+        }
+    }
+
+    /// Replaces all parameters set so far.
+    pub fn config(mut self, config: HeapConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn max_small_size(mut self, size: usize) -> Self {
+        self.config.max_small_size = size;
+        self
+    }
+
+    pub fn max_medium_size(mut self, size: usize) -> Self {
+        self.config.max_medium_size = size;
+        self
+    }
+
+    pub fn frame_cache_limit(mut self, frames: usize) -> Self {
+        self.config.frame_cache_limit = frames;
+        self
+    }
+
+    pub fn size_class_limit(mut self, classes: usize) -> Self {
+        self.config.size_class_limit = classes;
+        self
+    }
+
+    pub fn frame_refill_size(mut self, frames: usize) -> Self {
+        self.config.frame_refill_size = frames;
+        self
+    }
+
+    pub fn quantum_alloc_rounds(mut self, rounds: u32) -> Self {
+        self.config.quantum_alloc_rounds = rounds;
+        self
+    }
+
+    pub fn quantum_search_budget(mut self, budget: usize) -> Self {
+        self.config.quantum_search_budget = budget;
+        self
+    }
+
+    pub fn quantum_shards(mut self, shards: usize) -> Self {
+        self.config.quantum_shards = shards;
+        self
+    }
+
+    pub fn transfer_buffer_capacity(mut self, quanta: usize) -> Self {
+        self.config.transfer_buffer_capacity = Some(quanta);
+        self
+    }
+
+    pub fn max_physical_size(mut self, size: usize) -> Self {
+        self.config.max_physical_size = Some(size);
+        self
+    }
+
+    pub fn frame_growth_size(mut self, frames: usize) -> Self {
+        self.config.frame_growth_size = frames;
+        self
+    }
+
+    pub fn targeted_flush_limit(mut self, quanta: usize) -> Self {
+        self.config.targeted_flush_limit = quanta;
+        self
+    }
+
+    pub fn giant_frames(mut self, frames: usize) -> Self {
+        self.config.giant_frames = frames;
+        self
+    }
+
+    pub fn node_fallback(mut self, order: &'static [&'static [usize]]) -> Self {
+        self.config.node_fallback = Some(order);
+        self
+    }
+
+    /// Panics if the configuration is invalid, see [HeapConfig::validate].
+    pub fn build(self) -> GlobalData<S> {
+        if let Err(e) = self.config.validate::<S>() {
+            panic!("invalid heap configuration: {e}");
+        }
+        GlobalData::with_config(self.sys, self.physical_size, self.virt_size, self.config)
+    }
+}
@@ -1,15 +1,27 @@
-use std::{alloc::Layout, num::NonZeroUsize, ops::Deref, ptr::NonNull};
+use std::{
+    alloc::Layout,
+    mem::MaybeUninit,
+    num::NonZeroUsize,
+    ops::Deref,
+    ptr::{self, NonNull},
+};
 
 use crate::{
-    frame_list::FrameList2M,
-    myalloc::LocalCommon,
+    frame_list::HeapFrameList,
+    myalloc::{giant_pages::GIANT_PAGE_SIZE, LocalCommon},
     quantum_address::QuantumAddress,
-    util::{page_from_addr, unsafe_assert, vaddr_unchecked, PAGE_SIZE, VIRTUAL_QUANTUM_BITS},
+    util::{
+        page_from_addr, unsafe_assert, vaddr_unchecked, PAGE_SIZE, VIRTUAL_QUANTUM_BITS,
+        VIRTUAL_QUANTUM_SIZE,
+    },
     GlobalData, SystemInterface,
 };
 
+/// pages moved per walk when realloc moves an allocation.
+const MOVE_BATCH: usize = 64;
+
 #[inline]
-fn large_alloc_level(size: usize) -> u32 {
+pub fn large_alloc_level(size: usize) -> u32 {
     size.next_power_of_two()
         .trailing_zeros()
         .saturating_sub(VIRTUAL_QUANTUM_BITS)
@@ -20,43 +32,24 @@ pub fn alloc_large<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
     common: &mut LocalCommon<S, G>,
     layout: Layout,
 ) -> Option<NonNull<u8>> {
-    assert!(layout.align() <= PAGE_SIZE);
     let level = large_alloc_level(layout.size());
-    let quantum = common
-        .global
-        .quantum_storage
-        .alloc(level, &mut common.rng)?;
+    let quantum = if std::hint::likely(layout.align() <= VIRTUAL_QUANTUM_SIZE) {
+        common
+            .global
+            .quantum_storage
+            .alloc(level, &mut common.rng, common.quantum_shard)?
+    } else {
+        alloc_over_aligned(common, level, layout.align())?
+    };
     let start = quantum.start();
     let end = start + layout.size().next_multiple_of(PAGE_SIZE);
-    let mut to_map = start;
-    unsafe_assert!(to_map < end);
-    while to_map < end {
-        let remaining_frames = (end - to_map) / PAGE_SIZE;
-        let Some(frame) = common.available_frames.pop_with_refill(
-            &common.global.available_frames,
-            remaining_frames.min(FrameList2M::<S>::CAPACITY),
-        ) else {
-            std::hint::cold_path();
-            while to_map > start {
-                to_map -= PAGE_SIZE;
-                unsafe {
-                    let page = page_from_addr(vaddr_unchecked(to_map));
-                    let frame = common.global.sys.unmap(page);
-                    common
-                        .available_frames
-                        .push_with_spill(frame, &common.global.available_frames);
-                }
-            }
-            common
-                .available_frames
-                .release_extra_to_vec(&common.global.available_frames);
-            common.global.quantum_storage.dealloc_clean(level, quantum);
-            return None;
-        };
-        let page = unsafe { page_from_addr(vaddr_unchecked(to_map)) };
-        unsafe { common.global.sys.map(page, frame) };
-        to_map += PAGE_SIZE;
+    unsafe_assert!(start < end);
+    if map_fresh_frames(common, start, end).is_none() {
+        std::hint::cold_path();
+        common.global.quantum_storage.dealloc_clean(level, quantum);
+        return None;
     }
+    common.global.set_large_size(start, end - start);
     unsafe {
         Some(NonNull::with_exposed_provenance(
             NonZeroUsize::new_unchecked(start),
@@ -71,24 +64,224 @@ pub fn dealloc_large<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
     size: usize,
 ) {
     let level = large_alloc_level(size);
-    let mut to_unmap = ptr.addr();
-    let end = (to_unmap + size).next_multiple_of(PAGE_SIZE);
-    unsafe_assert!(to_unmap < end);
-    while to_unmap < end {
-        unsafe {
-            let page = page_from_addr(vaddr_unchecked(to_unmap));
-            let frame = common.global.sys.unmap(page);
-            common
-                .available_frames
-                .push_with_spill(frame, &common.global.available_frames);
-        }
-        to_unmap += PAGE_SIZE;
-    }
-    common
-        .available_frames
-        .release_extra_to_vec(&common.global.available_frames);
+    let start = ptr.addr();
+    let end = (start + size).next_multiple_of(PAGE_SIZE);
+    unsafe_assert!(start < end);
+    unmap_frames(common, start, end);
+    common.global.set_large_size(start, 0);
     common
         .global
         .quantum_storage
         .dealloc_dirty(level, QuantumAddress::from_start(ptr.addr()));
 }
+
+/// Buddy blocks are aligned to their size, so an allocation at a higher level is sufficiently aligned.
+/// The upper halves are returned, leaving a block of the requested level.
+#[cold]
+fn alloc_over_aligned<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
+    common: &mut LocalCommon<S, G>,
+    level: u32,
+    align: usize,
+) -> Option<QuantumAddress> {
+    let aligned_level = (align / VIRTUAL_QUANTUM_SIZE).ilog2().max(level);
+    if aligned_level >= common.global.quantum_storage.levels() {
+        return None;
+    }
+    let quantum = common.global.quantum_storage.alloc(
+        aligned_level,
+        &mut common.rng,
+        common.quantum_shard,
+    )?;
+    for l in level..aligned_level {
+        let buddy = quantum.start() + (VIRTUAL_QUANTUM_SIZE << l);
+        common
+            .global
+            .quantum_storage
+            .dealloc_clean(l, QuantumAddress::from_start(buddy));
+    }
+    Some(quantum)
+}
+
+/// Resizes a large allocation by changing its mappings.
+/// The contents are only copied when shrinking into part of a giant page,
+/// which moves up to a whole GiB, as giant pages are never split.
+/// On failure, the original allocation is left untouched.
+///
+/// # Safety
+/// ptr must be a large allocation of old_size, new_size must be a large size.
+pub unsafe fn realloc_large<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
+    common: &mut LocalCommon<S, G>,
+    ptr: NonNull<u8>,
+    old_size: usize,
+    new_size: usize,
+) -> Option<NonNull<u8>> {
+    let start = ptr.addr().get();
+    let old_level = large_alloc_level(old_size);
+    let new_level = large_alloc_level(new_size);
+    let old_end = start + old_size.next_multiple_of(PAGE_SIZE);
+    let new_end = start + new_size.next_multiple_of(PAGE_SIZE);
+    if new_end < old_end
+        && !new_end.is_multiple_of(GIANT_PAGE_SIZE)
+        && common.global.giant_pages.is_mapped(new_end)
+    {
+        // giant pages are never split.
+        let new_ptr = alloc_large(
+            common,
+            Layout::from_size_align_unchecked(new_size, PAGE_SIZE),
+        )?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), new_size);
+        dealloc_large(common, ptr.as_ptr(), old_size);
+        return Some(new_ptr);
+    }
+    if new_level > old_level {
+        let quantum = common.global.quantum_storage.alloc(
+            new_level,
+            &mut common.rng,
+            common.quantum_shard,
+        )?;
+        let new_start = quantum.start();
+        let moved_end = new_start + (old_end - start);
+        if map_fresh_frames(common, moved_end, new_start + (new_end - start)).is_none() {
+            common
+                .global
+                .quantum_storage
+                .dealloc_clean(new_level, quantum);
+            return None;
+        }
+        let mut offset = 0;
+        while offset < old_end - start {
+            if (start + offset).is_multiple_of(GIANT_PAGE_SIZE)
+                && common.global.giant_pages.remap(
+                    common.global.sys,
+                    start + offset,
+                    new_start + offset,
+                )
+            {
+                offset += GIANT_PAGE_SIZE;
+                continue;
+            }
+            // moved in batches up to the next GiB, which may be mapped by a giant page.
+            let chunk_end = old_end.min(((start + offset) | (GIANT_PAGE_SIZE - 1)) + 1);
+            let count = ((chunk_end - start - offset) / PAGE_SIZE).min(MOVE_BATCH);
+            let mut batch = [MaybeUninit::uninit(); MOVE_BATCH];
+            let sys = common.global.sys;
+            let mut i = 0;
+            sys.unmap_range(
+                page_from_addr(vaddr_unchecked(start + offset)),
+                count,
+                |frame| {
+                    batch[i].write(frame);
+                    i += 1;
+                },
+            );
+            let mut i = 0;
+            sys.map_range(
+                page_from_addr(vaddr_unchecked(new_start + offset)),
+                count,
+                || {
+                    i += 1;
+                    batch[i - 1].assume_init()
+                },
+            );
+            offset += count * PAGE_SIZE;
+        }
+        common.global.set_large_size(start, 0);
+        common.global.set_large_size(new_start, new_end - start);
+        common
+            .global
+            .quantum_storage
+            .dealloc_dirty(old_level, QuantumAddress::from_start(start));
+        return Some(NonNull::with_exposed_provenance(
+            NonZeroUsize::new_unchecked(new_start),
+        ));
+    }
+    if new_end > old_end {
+        map_fresh_frames(common, old_end, new_end)?;
+    } else {
+        unmap_frames(common, new_end, old_end);
+        // the tail may be mapped again by a later grow, and its frames reused right away.
+        common.global.sys.flush_tlb_range(new_end..old_end);
+    }
+    common.global.set_large_size(start, new_end - start);
+    // return the upper halves of the buddy block that are no longer needed.
+    for level in new_level..old_level {
+        let buddy = start + (VIRTUAL_QUANTUM_SIZE << level);
+        common
+            .global
+            .quantum_storage
+            .dealloc_dirty(level, QuantumAddress::from_start(buddy));
+    }
+    Some(ptr)
+}
+
+/// Maps newly claimed frames to the pages in start..end, using giant pages where possible.
+/// On failure, nothing is mapped.
+fn map_fresh_frames<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
+    common: &mut LocalCommon<S, G>,
+    start: usize,
+    end: usize,
+) -> Option<()> {
+    let mut to_map = start;
+    while to_map < end {
+        if to_map.is_multiple_of(GIANT_PAGE_SIZE)
+            && end - to_map >= GIANT_PAGE_SIZE
+            && common.global.giant_pages.try_map(common.global.sys, to_map)
+        {
+            to_map += GIANT_PAGE_SIZE;
+            continue;
+        }
+        // only claim frames up to the next GiB, which may be mapped by a giant page.
+        let chunk_end = end.min((to_map | (GIANT_PAGE_SIZE - 1)) + 1);
+        let count = ((chunk_end - to_map) / PAGE_SIZE).min(HeapFrameList::<S>::CAPACITY - 1);
+        if common
+            .available_frames
+            .steal_from_vec(&common.global.available_frames, count)
+            .is_none()
+        {
+            std::hint::cold_path();
+            unmap_frames(common, start, to_map);
+            return None;
+        }
+        let frames = &mut common.available_frames;
+        unsafe {
+            common
+                .global
+                .sys
+                .map_range(page_from_addr(vaddr_unchecked(to_map)), count, || {
+                    frames.pop().unwrap()
+                })
+        };
+        to_map += count * PAGE_SIZE;
+    }
+    Some(())
+}
+
+/// Unmaps the pages in start..end and keeps their frames.
+/// Giant pages must lie entirely within or outside the range.
+fn unmap_frames<S: SystemInterface, G: Deref<Target = GlobalData<S>>>(
+    common: &mut LocalCommon<S, G>,
+    start: usize,
+    end: usize,
+) {
+    let mut to_unmap = start;
+    while to_unmap < end {
+        if to_unmap.is_multiple_of(GIANT_PAGE_SIZE)
+            && common.global.giant_pages.unmap(common.global.sys, to_unmap)
+        {
+            to_unmap += GIANT_PAGE_SIZE;
+            continue;
+        }
+        let chunk_end = end.min((to_unmap | (GIANT_PAGE_SIZE - 1)) + 1);
+        let count = (chunk_end - to_unmap) / PAGE_SIZE;
+        let (frames, global) = (&mut common.available_frames, &common.global);
+        unsafe {
+            global
+                .sys
+                .unmap_range(page_from_addr(vaddr_unchecked(to_unmap)), count, |frame| {
+                    frames.push_with_spill(frame, &global.available_frames)
+                })
+        };
+        to_unmap = chunk_end;
+    }
+    common.release_extra_frames();
+}
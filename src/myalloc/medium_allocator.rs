@@ -1,5 +1,4 @@
 use crate::{
-    frame_list::FrameList2M,
     myalloc::LocalCommon,
     quantum_address::QuantumAddress,
     util::{
@@ -13,31 +12,84 @@ use std::{
     marker::PhantomData,
     mem,
     ops::Deref,
-    ptr::NonNull,
+    ptr::{self, NonNull},
     sync::atomic::{
-        AtomicUsize,
-        Ordering::{Acquire, Relaxed, Release},
+        AtomicU64, AtomicUsize,
+        Ordering::{AcqRel, Acquire, Relaxed, Release},
     },
 };
-use x86_64::{structures::paging::Page, VirtAddr};
 
 pub struct MediumAllocator<S: SystemInterface, G: Deref<Target = GlobalData<S>>> {
     bump: usize,
+    /// identifies the quanta owned by this allocator, assigned when claiming the first quantum.
+    id: usize,
+    /// footer of the first owned quantum, the others are linked through [OwnerState].
+    owned: usize,
     _p: PhantomData<fn() -> G>,
 }
 
+/// Quanta remain owned by the allocator that claimed them until it is dropped.
+/// Only the owner allocates from freed space. Other handles decrement the page counters of objects they free right away,
+/// and record the freed range in `remote_free` for the owner to reuse.
 struct BumpFooter {
     /// the page of the byte pointed to by bump has one count extra for the allocator.
     /// pages below that page in the bump region are set to 1.
+    /// Pages without objects are [EMPTY], and unmapped unless they belong to the footer.
     counts: [AtomicUsize; PAGES_PER_QUANTUM],
+    /// number of pages that are not [EMPTY], plus one while the quantum is owned.
     page_count: AtomicUsize,
+    /// 0 once the owner is dropped.
+    owner: AtomicUsize,
+    /// ranges freed by other handles, see [encode_remote], 0 if unused.
+    /// Ranges that find no free slot are not reused until the quantum is released.
+    remote_free: [AtomicU64; REMOTE_SLOTS],
+    /// size of the object starting in each [SIZE_GRANULE] of the quantum, see [record_size].
+    #[cfg(feature = "sizeless_free")]
+    sizes: [std::sync::atomic::AtomicU32; VIRTUAL_QUANTUM_SIZE / SIZE_GRANULE],
+    owner_state: OwnerState,
+}
+
+/// Only accessed by the owner.
+struct OwnerState {
+    prev: usize,
+    next: usize,
+    /// free ranges of the quantum sorted by address, their pages may or may not be mapped.
+    extent_count: usize,
+    extents: [Extent; MAX_EXTENTS],
+}
+
+#[derive(Clone, Copy)]
+struct Extent {
+    start: usize,
+    end: usize,
 }
 
 const PAGES_PER_QUANTUM: usize = VIRTUAL_QUANTUM_SIZE / PAGE_SIZE;
+/// the footer occupies the pages from this on, which remain mapped until the quantum is released.
+/// Only the first of them holds allocations, the counters of the others stay at zero.
+const FIRST_FOOTER_PAGE: usize =
+    PAGES_PER_QUANTUM - mem::size_of::<BumpFooter>().div_ceil(PAGE_SIZE);
+/// once there are more free ranges, the smallest is dropped.
+/// Its pages are still unmapped once empty, but the range is not reused until the quantum is released.
+const MAX_EXTENTS: usize = 32;
+/// ranges freed by other handles between two drains by the owner, beyond that they are lost the same way.
+/// The owner drains a quantum when it frees into it, and all of them before mapping another bump page.
+const REMOTE_SLOTS: usize = 32;
+/// medium objects are larger than this, so no two of them start in the same granule.
+#[cfg(feature = "sizeless_free")]
+pub const SIZE_GRANULE: usize = PAGE_SIZE / 16;
+/// page counter of a page without objects.
+const EMPTY: usize = usize::MAX;
+/// page counter while the page is being unmapped, after which it becomes [EMPTY].
+const RETIRING: usize = usize::MAX - 1;
+/// page counter while the owner maps an [EMPTY] page again.
+const REVIVING: usize = usize::MAX - 2;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 
 impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> Drop for MediumAllocator<S, G> {
     fn drop(&mut self) {
-        assert!(self.bump == 0);
+        assert!(self.bump == 0 && self.owned == 0);
     }
 }
 
@@ -46,15 +98,86 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> MediumAllocator<S, G>
     pub const fn new() -> Self {
         MediumAllocator {
             bump: 0,
+            id: 0,
+            owned: 0,
             _p: PhantomData,
         }
     }
 
-    #[inline]
     pub fn deinit(&mut self, common: &mut LocalCommon<S, G>) {
-        if std::hint::likely(self.bump != 0) {
-            unsafe { Self::decrement_page_counter(common, self.bump) };
-            self.bump = 0;
+        self.retire_bump(common);
+        while self.owned != 0 {
+            let footer = self.owned as *mut BumpFooter;
+            unsafe {
+                self.unlink(footer);
+                // from now on, other handles no longer record freed ranges.
+                (*footer).owner.store(0, Relaxed);
+                if (*footer).page_count.fetch_sub(1, Release) == 1 {
+                    (*footer).page_count.load(Acquire);
+                    Self::release_quantum(common, footer.addr());
+                }
+            }
+        }
+    }
+
+    /// Stops bump allocating in the current quantum, the space below the bump pointer becomes a free extent.
+    #[inline]
+    fn retire_bump(&mut self, common: &mut LocalCommon<S, G>) {
+        if let Some(footer) = self.retire_bump_keep_quantum(common) {
+            unsafe { self.release_if_unused(common, footer) };
+        }
+    }
+
+    fn retire_bump_keep_quantum(
+        &mut self,
+        common: &mut LocalCommon<S, G>,
+    ) -> Option<*mut BumpFooter> {
+        if std::hint::unlikely(self.bump == 0) {
+            return None;
+        }
+        let bump = self.bump;
+        self.bump = 0;
+        let footer = find_footer(bump);
+        unsafe {
+            Self::retire_unmapped_pages(bump);
+            Self::decrement_page_counter(common, bump);
+            insert_extent(footer, align_down_const::<VIRTUAL_QUANTUM_SIZE>(bump), bump);
+        }
+        Some(footer)
+    }
+
+    /// The bump pointer cannot skip pages, so allocations aligned to more than a page are placed in free extents.
+    /// If none fits, a fresh quantum is turned into a free extent.
+    #[cold]
+    fn alloc_over_aligned(
+        &mut self,
+        common: &mut LocalCommon<S, G>,
+        layout: Layout,
+    ) -> Option<NonNull<u8>> {
+        // the placed size is at least the alignment, so larger alignments go to the large tier.
+        unsafe_assert!(layout.align() <= VIRTUAL_QUANTUM_SIZE / 2);
+        self.drain_remote(common);
+        if let Some(ptr) = self.alloc_from_extents(common, layout) {
+            return Some(ptr);
+        }
+        self.claim_quantum(common)?;
+        let footer = self.retire_bump_keep_quantum(common).unwrap();
+        let ptr = self.alloc_from_extents(common, layout);
+        unsafe { self.release_if_unused(common, footer) };
+        ptr
+    }
+
+    /// Pages below the page containing `bump` have never been mapped.
+    /// They still hold their initial count, which must be dropped for the quantum to be released.
+    unsafe fn retire_unmapped_pages(bump: usize) {
+        let footer = unsafe { &*find_footer(bump) };
+        let unmapped_pages = bump / PAGE_SIZE % PAGES_PER_QUANTUM;
+        if unmapped_pages > 0 {
+            for c in &footer.counts[..unmapped_pages] {
+                c.store(EMPTY, Relaxed);
+            }
+            // the page containing bump still holds a count, so this never drops to zero.
+            footer.page_count.fetch_sub(unmapped_pages, Release);
         }
     }
 
@@ -68,18 +191,28 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> MediumAllocator<S, G>
     ) -> Option<NonNull<u8>> {
         unsafe_assert!(layout.size() > 0);
         unsafe_assert!(layout.size() <= VIRTUAL_QUANTUM_SIZE / 2);
+        if std::hint::unlikely(layout.align() > PAGE_SIZE) {
+            return self.alloc_over_aligned(common, layout);
+        }
+        let mut tried_extents = false;
         loop {
             let new_bump =
                 unsafe { align_down(self.bump.wrapping_sub(layout.size()), layout.align()) };
             let mut page_limit = align_down_const::<PAGE_SIZE>(self.bump);
             if wrapping_less_than(new_bump, page_limit) {
+                if !tried_extents {
+                    tried_extents = true;
+                    self.drain_remote(common);
+                    if let Some(ptr) = self.alloc_from_extents(common, layout) {
+                        return Some(ptr);
+                    }
+                }
                 let bump_limit = align_down_const::<VIRTUAL_QUANTUM_SIZE>(self.bump);
-                assert!(layout.align() <= PAGE_SIZE);
                 if std::hint::unlikely(wrapping_less_than(new_bump, bump_limit)) {
                     self.claim_quantum(common)?;
                     continue;
                 }
-                let allocation_end = self.bump + layout.size();
+                let allocation_end = new_bump + layout.size();
                 if std::hint::unlikely(allocation_end <= page_limit) {
                     unsafe {
                         Self::decrement_page_counter(common, self.bump);
@@ -91,18 +224,15 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> MediumAllocator<S, G>
                 common
                     .available_frames
                     .steal_from_vec(&common.global.available_frames, missing_pages)?;
-                while page_limit > new_page_limit {
-                    page_limit -= PAGE_SIZE;
-                    unsafe_assert!(page_limit.is_multiple_of(PAGE_SIZE));
-                    unsafe {
-                        common.global.sys.map(
-                            Page::from_start_address_unchecked(VirtAddr::new_unsafe(
-                                page_limit as u64,
-                            )),
-                            common.available_frames.pop().unwrap(),
-                        );
-                    }
+                let frames = &mut common.available_frames;
+                unsafe {
+                    common.global.sys.map_range(
+                        page_from_addr(vaddr_unchecked(new_page_limit)),
+                        missing_pages,
+                        || frames.pop().unwrap(),
+                    );
                 }
+                page_limit = new_page_limit;
             }
             self.bump = new_bump;
             let page_index = page_limit / PAGE_SIZE % PAGES_PER_QUANTUM;
@@ -119,15 +249,210 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> MediumAllocator<S, G>
     /// # Safety
     /// ptr must be allocated with size
     #[inline]
-    pub unsafe fn dealloc(common: &mut LocalCommon<S, G>, ptr: *mut u8, size: usize) {
-        let start = align_down_const::<PAGE_SIZE>(ptr.addr());
-        let end = align_up_const::<PAGE_SIZE>(ptr.addr() + size);
+    pub unsafe fn dealloc(&mut self, common: &mut LocalCommon<S, G>, ptr: *mut u8, size: usize) {
+        let footer = find_footer(ptr.addr());
+        if self.id != 0 && (*footer).owner.load(Relaxed) == self.id {
+            self.free_owned(common, footer, ptr.addr(), size);
+            return;
+        }
+        // recorded first, the object keeps the quantum alive until its pages are decremented.
+        if (*footer).owner.load(Relaxed) != 0 {
+            let encoded = encode_remote(ptr.addr(), size);
+            for slot in &(*footer).remote_free {
+                if slot.load(Relaxed) == 0
+                    && slot.compare_exchange(0, encoded, Release, Relaxed).is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        Self::decrement_range(common, ptr.addr(), size);
+    }
+
+    unsafe fn free_owned(
+        &mut self,
+        common: &mut LocalCommon<S, G>,
+        footer: *mut BumpFooter,
+        start: usize,
+        size: usize,
+    ) {
+        Self::decrement_range(common, start, size);
+        insert_extent(footer, start, start + size);
+        Self::drain_remote_of(footer);
+        self.release_if_unused(common, footer);
+    }
+
+    unsafe fn decrement_range(common: &mut LocalCommon<S, G>, start: usize, size: usize) {
+        let end = align_up_const::<PAGE_SIZE>(start + size);
+        let start = align_down_const::<PAGE_SIZE>(start);
         unsafe_assert!(start < end);
         for i in (start..end).step_by(PAGE_SIZE) {
             Self::decrement_page_counter(common, i);
         }
     }
 
+    /// Adds the ranges freed by other handles to the free extents.
+    unsafe fn drain_remote_of(footer: *mut BumpFooter) {
+        let quantum_start = align_down_const::<VIRTUAL_QUANTUM_SIZE>(footer.addr());
+        for slot in &(*footer).remote_free {
+            if slot.load(Relaxed) != 0 {
+                let (start, end) = decode_remote(quantum_start, slot.swap(0, Acquire));
+                insert_extent(footer, start, end);
+            }
+        }
+    }
+
+    /// Takes the space freed by other handles into account, releasing quanta that became unused.
+    fn drain_remote(&mut self, common: &mut LocalCommon<S, G>) {
+        let mut footer = self.owned as *mut BumpFooter;
+        while !footer.is_null() {
+            unsafe {
+                let next = (*footer).owner_state.next as *mut BumpFooter;
+                Self::drain_remote_of(footer);
+                self.release_if_unused(common, footer);
+                footer = next;
+            }
+        }
+    }
+
+    /// Places an allocation in the smallest fitting free extent of any owned quantum.
+    /// Space freed by other handles is only found after [drain_remote](Self::drain_remote).
+    #[cold]
+    fn alloc_from_extents(
+        &mut self,
+        common: &mut LocalCommon<S, G>,
+        layout: Layout,
+    ) -> Option<NonNull<u8>> {
+        let mut best: Option<(*mut BumpFooter, usize, usize)> = None;
+        let mut best_len = usize::MAX;
+        let mut footer = self.owned as *mut BumpFooter;
+        while !footer.is_null() {
+            let state = unsafe { &(*footer).owner_state };
+            for (i, e) in state.extents[..state.extent_count].iter().enumerate() {
+                let len = e.end - e.start;
+                if len < layout.size() || len >= best_len {
+                    continue;
+                }
+                let start = unsafe { align_down(e.end - layout.size(), layout.align()) };
+                if start >= e.start {
+                    best = Some((footer, i, start));
+                    best_len = len;
+                }
+            }
+            footer = state.next as *mut BumpFooter;
+        }
+        let (footer, index, start) = best?;
+        let end = start + layout.size();
+        let first_page = align_down_const::<PAGE_SIZE>(start);
+        let pages = (first_page..end).step_by(PAGE_SIZE);
+        let page_index = |page: usize| page / PAGE_SIZE % PAGES_PER_QUANTUM;
+        unsafe {
+            // claim every page, marking those without objects for mapping.
+            let mut revived = 0;
+            for page in pages.clone() {
+                let count = &(*footer).counts[page_index(page)];
+                let mut current = count.load(Relaxed);
+                loop {
+                    let new = match current {
+                        EMPTY => REVIVING,
+                        RETIRING => {
+                            // another handle freed the last object and is unmapping the page.
+                            std::hint::spin_loop();
+                            current = count.load(Relaxed);
+                            continue;
+                        }
+                        c => c + 1,
+                    };
+                    match count.compare_exchange_weak(current, new, Acquire, Relaxed) {
+                        Ok(_) => break,
+                        Err(c) => current = c,
+                    }
+                }
+                if current == EMPTY {
+                    revived += 1;
+                }
+            }
+            // the footer pages are never unmapped.
+            let unmapped = pages
+                .clone()
+                .filter(|&p| {
+                    page_index(p) < FIRST_FOOTER_PAGE
+                        && (*footer).counts[page_index(p)].load(Relaxed) == REVIVING
+                })
+                .count();
+            if common
+                .available_frames
+                .steal_from_vec(&common.global.available_frames, unmapped)
+                .is_none()
+            {
+                for page in pages {
+                    let count = &(*footer).counts[page_index(page)];
+                    if count.load(Relaxed) == REVIVING {
+                        count.store(EMPTY, Relaxed);
+                    } else {
+                        Self::decrement_page_counter(common, page);
+                    }
+                }
+                return None;
+            }
+            if unmapped > 0 {
+                // other cores may still translate the pages to the frames they had before being unmapped.
+                common
+                    .global
+                    .sys
+                    .flush_tlb_range(first_page..align_up_const::<PAGE_SIZE>(end));
+            }
+            for page in pages {
+                let i = page_index(page);
+                if (*footer).counts[i].load(Relaxed) == REVIVING {
+                    if i < FIRST_FOOTER_PAGE {
+                        common.global.sys.map(
+                            page_from_addr(vaddr_unchecked(page)),
+                            common.available_frames.pop().unwrap(),
+                        );
+                    }
+                    (*footer).counts[i].store(1, Relaxed);
+                }
+            }
+            (*footer).page_count.fetch_add(revived, Relaxed);
+            let extent = remove_extent(footer, index);
+            insert_extent(footer, extent.start, start);
+            insert_extent(footer, end, extent.end);
+            Some(NonNull::new_unchecked(vaddr_unchecked(start).as_mut_ptr()))
+        }
+    }
+
+    /// Releases a quantum that is neither allocated from nor contains live objects.
+    unsafe fn release_if_unused(
+        &mut self,
+        common: &mut LocalCommon<S, G>,
+        footer: *mut BumpFooter,
+    ) {
+        let is_bump_quantum = self.bump != 0 && find_footer(self.bump) == footer;
+        // other handles only ever decrement the counters of their objects, which are not counted anymore.
+        if !is_bump_quantum
+            && (*footer)
+                .page_count
+                .compare_exchange(1, 0, Acquire, Relaxed)
+                .is_ok()
+        {
+            self.unlink(footer);
+            Self::release_quantum(common, footer.addr());
+        }
+    }
+
+    unsafe fn unlink(&mut self, footer: *mut BumpFooter) {
+        let OwnerState { prev, next, .. } = (*footer).owner_state;
+        if prev == 0 {
+            self.owned = next;
+        } else {
+            (*(prev as *mut BumpFooter)).owner_state.next = next;
+        }
+        if next != 0 {
+            (*(next as *mut BumpFooter)).owner_state.prev = prev;
+        }
+    }
+
     #[inline]
     unsafe fn decrement_page_counter(common: &mut LocalCommon<S, G>, address_in_page: usize) {
         let footer = find_footer(address_in_page);
@@ -141,58 +466,191 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> MediumAllocator<S, G>
     unsafe fn on_page_counter_zero(common: &mut LocalCommon<S, G>, address_in_page: usize) {
         let footer = find_footer(address_in_page);
         let page_index = address_in_page / PAGE_SIZE % PAGES_PER_QUANTUM;
-        let dealloc_quantum = unsafe {
-            (*footer).counts[page_index].load(Acquire);
-            (*footer).page_count.fetch_sub(1, Acquire) == 1
-        };
-        if page_index < PAGES_PER_QUANTUM - 1 {
+        let count = unsafe { &(*footer).counts[page_index] };
+        // the owner may have placed a new object in the page in the meantime.
+        if count
+            .compare_exchange(0, RETIRING, Acquire, Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        if page_index < FIRST_FOOTER_PAGE {
             Self::dealloc_page(common, address_in_page);
         }
+        count.store(EMPTY, Release);
+        let dealloc_quantum = unsafe { (*footer).page_count.fetch_sub(1, AcqRel) == 1 };
         if dealloc_quantum {
-            Self::dealloc_page(common, address_in_page | (VIRTUAL_QUANTUM_SIZE - 1));
-            common
-                .global
-                .quantum_storage
-                .dealloc_dirty(0, QuantumAddress::containing(address_in_page));
+            Self::release_quantum(common, address_in_page);
         }
     }
 
+    unsafe fn release_quantum(common: &mut LocalCommon<S, G>, address_in_quantum: usize) {
+        let quantum_start = align_down_const::<VIRTUAL_QUANTUM_SIZE>(address_in_quantum);
+        let (frames, global) = (&mut common.available_frames, &common.global);
+        global.sys.unmap_range(
+            page_from_addr(vaddr_unchecked(
+                quantum_start + FIRST_FOOTER_PAGE * PAGE_SIZE,
+            )),
+            PAGES_PER_QUANTUM - FIRST_FOOTER_PAGE,
+            |frame| frames.push_with_spill(frame, &global.available_frames),
+        );
+        common.release_extra_frames();
+        common
+            .global
+            .quantum_storage
+            .dealloc_dirty(0, QuantumAddress::containing(address_in_quantum));
+    }
+
     unsafe fn dealloc_page(common: &mut LocalCommon<S, G>, address_in_page: usize) {
         let page = align_down_const::<PAGE_SIZE>(address_in_page);
         let page = unsafe { page_from_addr(vaddr_unchecked(page)) };
         let frame = unsafe { common.global.sys.unmap(page) };
         common.available_frames.push(frame).unwrap();
-        common
-            .available_frames
-            .release_extra_to_vec(&common.global.available_frames);
+        common.release_extra_frames();
     }
 
     fn claim_quantum(&mut self, common: &mut LocalCommon<S, G>) -> Option<()> {
-        self.deinit(common);
-        let quantum = common.global.quantum_storage.alloc(0, &mut common.rng)?;
-        let last_page = quantum.start() + (PAGES_PER_QUANTUM - 1) * PAGE_SIZE;
-        let last_page = unsafe { page_from_addr(vaddr_unchecked(last_page)) };
-        let Some(frame) = common.available_frames.pop_with_refill(
+        self.retire_bump(common);
+        let quantum =
+            common
+                .global
+                .quantum_storage
+                .alloc(0, &mut common.rng, common.quantum_shard)?;
+        let footer_pages = PAGES_PER_QUANTUM - FIRST_FOOTER_PAGE;
+        // taking fewer frames than the refill size is fine as long as the footer can be mapped.
+        let _ = common.available_frames.steal_from_vec(
             &common.global.available_frames,
-            FrameList2M::<S>::DEFAULT_REFILL_SIZE,
-        ) else {
+            footer_pages.max(common.global.config.frame_refill_size),
+        );
+        if common.available_frames.count() < footer_pages {
             common.global.quantum_storage.dealloc_clean(0, quantum);
             return None;
+        }
+        let frames = &mut common.available_frames;
+        unsafe {
+            common.global.sys.map_range(
+                page_from_addr(vaddr_unchecked(
+                    quantum.start() + FIRST_FOOTER_PAGE * PAGE_SIZE,
+                )),
+                footer_pages,
+                || frames.pop().unwrap(),
+            )
         };
-        unsafe { common.global.sys.map(last_page, frame) };
-        let footer = unsafe { &*find_footer(last_page.start_address().as_u64() as usize) };
-        for c in &footer.counts {
-            c.store(1, Relaxed);
+        if self.id == 0 {
+            self.id = NEXT_ID.fetch_add(1, Relaxed);
+        }
+        let footer = find_footer(quantum.start());
+        unsafe {
+            // written in place, the footer spans several pages when they are small.
+            let counts = ptr::addr_of_mut!((*footer).counts).cast::<AtomicUsize>();
+            for i in 0..PAGES_PER_QUANTUM {
+                counts
+                    .add(i)
+                    .write(AtomicUsize::new(if i <= FIRST_FOOTER_PAGE {
+                        1
+                    } else {
+                        EMPTY
+                    }));
+            }
+            ptr::addr_of_mut!((*footer).page_count).write(AtomicUsize::new(FIRST_FOOTER_PAGE + 2));
+            ptr::addr_of_mut!((*footer).owner).write(AtomicUsize::new(self.id));
+            ptr::addr_of_mut!((*footer).remote_free)
+                .write([const { AtomicU64::new(0) }; REMOTE_SLOTS]);
+            ptr::addr_of_mut!((*footer).owner_state).write(OwnerState {
+                prev: 0,
+                next: self.owned,
+                extent_count: 0,
+                extents: [Extent { start: 0, end: 0 }; MAX_EXTENTS],
+            });
+            if self.owned != 0 {
+                (*(self.owned as *mut BumpFooter)).owner_state.prev = footer.addr();
+            }
         }
-        footer.page_count.store(footer.counts.len(), Relaxed);
-        self.bump = align_down_const::<64>((footer as *const BumpFooter).addr());
+        self.owned = footer.addr();
+        self.bump = align_down_const::<64>(footer.addr());
         Some(())
     }
 }
 
+/// Remembers the size of the medium object at `ptr` for [allocation_size].
+#[cfg(feature = "sizeless_free")]
+#[inline]
+pub unsafe fn record_size(ptr: NonNull<u8>, size: usize) {
+    let addr = ptr.addr().get();
+    (*find_footer(addr)).sizes[addr % VIRTUAL_QUANTUM_SIZE / SIZE_GRANULE]
+        .store(size as u32, Relaxed);
+}
+
+/// The size the medium object at `ptr` was allocated with.
+#[cfg(feature = "sizeless_free")]
+#[inline]
+pub unsafe fn allocation_size(ptr: NonNull<u8>) -> usize {
+    let addr = ptr.addr().get();
+    (*find_footer(addr)).sizes[addr % VIRTUAL_QUANTUM_SIZE / SIZE_GRANULE].load(Relaxed) as usize
+}
+
+unsafe fn insert_extent(footer: *mut BumpFooter, start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+    let state = &mut (*footer).owner_state;
+    let count = state.extent_count;
+    let extents = &mut state.extents;
+    let i = extents[..count].partition_point(|e| e.start < start);
+    let merge_prev = i > 0 && extents[i - 1].end == start;
+    let merge_next = i < count && extents[i].start == end;
+    match (merge_prev, merge_next) {
+        (true, true) => {
+            extents[i - 1].end = extents[i].end;
+            remove_extent(footer, i);
+        }
+        (true, false) => extents[i - 1].end = end,
+        (false, true) => extents[i].start = start,
+        (false, false) => {
+            if count == MAX_EXTENTS {
+                let (smallest, e) = extents
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.end - e.start)
+                    .unwrap();
+                if e.end - e.start >= end - start {
+                    return;
+                }
+                remove_extent(footer, smallest);
+                return insert_extent(footer, start, end);
+            }
+            extents.copy_within(i..count, i + 1);
+            extents[i] = Extent { start, end };
+            state.extent_count += 1;
+        }
+    }
+}
+
+/// Packs a range freed by another handle into a [BumpFooter::remote_free] slot.
+fn encode_remote(start: usize, size: usize) -> u64 {
+    let offset = start % VIRTUAL_QUANTUM_SIZE;
+    unsafe_assert!(size > 0 && size < 1 << 32);
+    ((offset as u64) << 32) | size as u64
+}
+
+fn decode_remote(quantum_start: usize, encoded: u64) -> (usize, usize) {
+    let start = quantum_start + (encoded >> 32) as usize;
+    (start, start + (encoded as u32) as usize)
+}
+
+unsafe fn remove_extent(footer: *mut BumpFooter, index: usize) -> Extent {
+    let state = &mut (*footer).owner_state;
+    let extent = state.extents[index];
+    state
+        .extents
+        .copy_within(index + 1..state.extent_count, index);
+    state.extent_count -= 1;
+    extent
+}
+
 #[inline]
-fn find_footer(addr: usize) -> *const BumpFooter {
+fn find_footer(addr: usize) -> *mut BumpFooter {
     let max_addr = addr | (VIRTUAL_QUANTUM_SIZE - 1);
     let address = max_addr - (mem::size_of::<BumpFooter>() - 1);
-    address as *const BumpFooter
+    address as *mut BumpFooter
 }
@@ -1,4 +1,5 @@
 use crate::{
+    myalloc::HeapConfig,
     quantum_address::QuantumAddress,
     util::{unsafe_assert, VIRTUAL_QUANTUM_SIZE},
     SystemInterface,
@@ -6,103 +7,267 @@ use crate::{
 use buddy_bitmap::BuddyTower;
 use log::{error, warn};
 use rand::Rng;
-use std::sync::{atomic::Ordering::Relaxed, Mutex};
+use std::alloc::Allocator;
+use std::sync::atomic::{
+    fence,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::Mutex;
 use std::{ops::Range, sync::atomic::AtomicUsize};
 
+/// The quantum space, split into shards that recycle independently.
+/// Shards other than the last have the same power-of-two size, and blocks never span shards.
 pub struct QuantumStorage<S: SystemInterface> {
     quantum_base: AtomicUsize,
-    available_quanta: BuddyTower<S::Alloc>,
-    released_quanta: BuddyTower<S::Alloc>,
-    transfer_buffer: Mutex<Vec<u32, S::Alloc>>,
+    shards: Vec<Shard<S>, S::Alloc>,
+    shard_quanta: usize,
+    /// hands out the shards handles allocate from first, in turn.
+    next_home: AtomicUsize,
+    alloc_rounds: u32,
+    search_budget: usize,
+    targeted_flush_limit: usize,
     sys: S,
 }
 
-const QUANTUM_ID_BITS: u32 = 27;
-const QUANTUM_ID_MASK: u32 = (1 << QUANTUM_ID_BITS) - 1;
-const TRANSFER_BUFFER_LEVEL_BITS: u32 = 32 - QUANTUM_ID_BITS;
+struct Shard<S: SystemInterface> {
+    available_quanta: BuddyTower<S::Alloc>,
+    /// indexed by the flush epoch at release modulo 3, see [SystemInterface::tlb_flush_epoch].
+    released_quanta: [BuddyTower<S::Alloc>; 3],
+    transfer_buffer: Mutex<Vec<u64, S::Alloc>>,
+}
+
+/// enough for any arena with 5-level paging.
+const QUANTUM_ID_BITS: u32 = 48;
+const QUANTUM_ID_MASK: u64 = (1 << QUANTUM_ID_BITS) - 1;
+const TRANSFER_BUFFER_LEVEL_BITS: u32 = 64 - QUANTUM_ID_BITS;
 
 impl<S: SystemInterface> QuantumStorage<S> {
-    pub fn alloc(&self, level: u32, rng: &mut impl Rng) -> Option<QuantumAddress> {
-        for _ in 0..32 {
-            if let Some(x) = self.available_quanta.remove(level, rng, 8 * 64 * 16) {
-                let base = self.quantum_base.load(Relaxed);
-                // if a quantum was found, the storage must have been initialised.
-                debug_assert!(base != 0);
-                let addr = x * VIRTUAL_QUANTUM_SIZE + base;
-                unsafe_assert!(addr != 0);
-                return Some(QuantumAddress::from_start(addr));
+    /// Tries the `home` shard first, then the others.
+    /// Once all of them are exhausted, released quanta are recycled, starting from the next shard each round.
+    pub fn alloc(&self, level: u32, rng: &mut impl Rng, home: usize) -> Option<QuantumAddress> {
+        for round in 0..self.alloc_rounds as usize {
+            for i in 0..self.shards.len() {
+                let shard = (home + i) % self.shards.len();
+                if let Some(x) =
+                    self.shards[shard]
+                        .available_quanta
+                        .remove(level, rng, self.search_budget)
+                {
+                    let base = self.quantum_base.load(Relaxed);
+                    // if a quantum was found, the storage must have been initialised.
+                    debug_assert!(base != 0);
+                    let addr = (shard * self.shard_quanta + x) * VIRTUAL_QUANTUM_SIZE + base;
+                    unsafe_assert!(addr != 0);
+                    return Some(QuantumAddress::from_start(addr));
+                }
             }
-            self.recycle();
+            self.recycle((home + round) % self.shards.len());
         }
         error!("failed to reclaim sufficient virtual memory");
         None
     }
 
-    fn recycle(&self) {
-        if let Ok(mut tb) = self.transfer_buffer.try_lock() {
-            let insert_transfer_vector = |transfer_buffer: &mut Vec<u32, S::Alloc>| {
-                self.sys.global_tlb_flush();
+    /// Start of the arena.
+    #[cfg(feature = "sizeless_free")]
+    pub fn base(&self) -> usize {
+        self.quantum_base.load(Relaxed)
+    }
+
+    /// Allocations at this or a higher level always fail.
+    pub fn levels(&self) -> u32 {
+        self.shards[0].available_quanta.levels() as u32
+    }
+
+    /// The shard a new handle should allocate from first.
+    pub fn home_shard(&self) -> usize {
+        self.next_home.fetch_add(1, Relaxed) % self.shards.len()
+    }
+
+    /// Recycles the first shard from `start` on that no other thread is recycling.
+    /// If all of them are, waits for the one at `start`.
+    fn recycle(&self, start: usize) {
+        for i in 0..self.shards.len() {
+            let shard = (start + i) % self.shards.len();
+            if let Ok(mut tb) = self.shards[shard].transfer_buffer.try_lock() {
+                self.recycle_shard(shard, &mut tb);
+                return;
+            }
+        }
+        self.sys.trace_recycle_backoff();
+        // recycling in progress everywhere, just wait for it to be done.
+        drop(self.shards[start].transfer_buffer.lock());
+    }
+
+    fn recycle_shard(&self, shard: usize, tb: &mut Vec<u64, S::Alloc>) {
+        let Shard {
+            available_quanta,
+            released_quanta,
+            ..
+        } = &self.shards[shard];
+        // quanta drained as clean must be flushed after all if the epoch moved while draining,
+        // as some may have been released at the new epoch.
+        let insert_transfer_vector =
+            |transfer_buffer: &mut Vec<u64, S::Alloc>, clean_epoch: Option<u64>| {
+                fence(Acquire);
+                if clean_epoch != Some(self.sys.tlb_flush_epoch()) {
+                    self.flush_tlb(shard, transfer_buffer);
+                }
                 for &x in &*transfer_buffer {
                     let level = x >> QUANTUM_ID_BITS;
                     let quantum_id = x & QUANTUM_ID_MASK;
-                    self.available_quanta.insert(quantum_id as usize, level)
+                    available_quanta.insert(quantum_id as usize, level as u32)
                 }
                 transfer_buffer.clear();
             };
-            assert!(tb.is_empty());
-            let levels = self.released_quanta.levels();
-            assert!(levels <= (1 << TRANSFER_BUFFER_LEVEL_BITS));
-            for level in 0..levels {
-                for quantum in self.released_quanta.drain_level(level) {
-                    if tb.len() == tb.capacity() {
-                        warn!("transfer vector full!");
-                        insert_transfer_vector(&mut tb);
-                    }
-                    let transfer_encoded = ((level as u32) << QUANTUM_ID_BITS) | quantum as u32;
-                    tb.push(transfer_encoded);
-                }
+        assert!(tb.is_empty());
+        // read once, so all clean quanta are checked against the same epoch.
+        let epoch = self.sys.tlb_flush_epoch();
+        if epoch >= 2 {
+            let clean = &released_quanta[((epoch - 2) % 3) as usize];
+            if drain_released(clean, tb, |tb| insert_transfer_vector(tb, Some(epoch))) {
+                insert_transfer_vector(tb, Some(epoch));
+                self.sys.trace_recycle();
+                return;
             }
-            self.sys.trace_recycle();
-            insert_transfer_vector(&mut tb);
-        } else {
-            self.sys.trace_recycle_backoff();
-            // recycling in progress, just wait for it to be done.
-            drop(self.transfer_buffer.lock());
         }
+        for released in released_quanta {
+            drain_released(released, tb, |tb| {
+                warn!("transfer vector full!");
+                insert_transfer_vector(tb, None)
+            });
+        }
+        self.sys.trace_recycle();
+        insert_transfer_vector(tb, None);
     }
 
-    pub fn dealloc_clean(&self, level: u32, quantum: QuantumAddress) {
+    /// Flushes the blocks of `shard` in the transfer buffer,
+    /// or everything if there are too many or the system cannot flush ranges.
+    fn flush_tlb(&self, shard: usize, transfer_buffer: &[u64]) {
+        if transfer_buffer.len() > self.targeted_flush_limit || !self.sys.has_range_flush() {
+            self.sys.global_tlb_flush();
+            return;
+        }
+        let base =
+            self.quantum_base.load(Relaxed) + shard * self.shard_quanta * VIRTUAL_QUANTUM_SIZE;
+        for &x in transfer_buffer {
+            let level = x >> QUANTUM_ID_BITS;
+            let start = base + (x & QUANTUM_ID_MASK) as usize * VIRTUAL_QUANTUM_SIZE;
+            self.sys
+                .flush_tlb_range(start..start + (VIRTUAL_QUANTUM_SIZE << level));
+        }
+    }
+
+    /// The shard owning `quantum` and its index within it.
+    fn locate(&self, quantum: QuantumAddress) -> (&Shard<S>, usize) {
         let index = (quantum.start() - self.quantum_base.load(Relaxed)) / VIRTUAL_QUANTUM_SIZE;
-        debug_assert!(index < 1 << 31);
-        self.available_quanta.insert(index, level);
+        debug_assert!(index < 1 << QUANTUM_ID_BITS);
+        (
+            &self.shards[index / self.shard_quanta],
+            index % self.shard_quanta,
+        )
+    }
+
+    pub fn dealloc_clean(&self, level: u32, quantum: QuantumAddress) {
+        let (shard, index) = self.locate(quantum);
+        shard.available_quanta.insert(index, level);
     }
 
     pub fn dealloc_dirty(&self, level: u32, quantum: QuantumAddress) {
-        let index = (quantum.start() - self.quantum_base.load(Relaxed)) / VIRTUAL_QUANTUM_SIZE;
-        debug_assert!(index < 1 << 31);
-        self.released_quanta.insert(index, level);
+        let (shard, index) = self.locate(quantum);
+        // read after the caller unmapped the quantum.
+        let epoch = self.sys.tlb_flush_epoch();
+        // a recycler that drains this quantum then sees at least this epoch.
+        fence(Release);
+        shard.released_quanta[(epoch % 3) as usize].insert(index, level);
+    }
+
+    /// Quanta per shard, a single shard keeps the whole arena as one buddy tower.
+    fn shard_quanta(quantum_count: usize, config: &HeapConfig) -> usize {
+        if config.quantum_shards == 1 {
+            quantum_count
+        } else {
+            quantum_count
+                .div_ceil(config.quantum_shards)
+                .next_power_of_two()
+        }
     }
 
-    pub fn from_range(sys: S, range: Range<QuantumAddress>) -> Self {
+    /// The level of the largest block handed out, the arena must be aligned to its size.
+    /// Blocks are at most a quarter of the arena, so the aligned arena takes at most 1.25 times its size to reserve.
+    pub fn top_level(quantum_count: usize, config: &HeapConfig) -> u32 {
+        let shard_quanta = Self::shard_quanta(quantum_count, config).min(quantum_count);
+        shard_quanta.ilog2().min((quantum_count / 4).max(1).ilog2())
+    }
+
+    pub fn from_range(sys: S, range: Range<QuantumAddress>, config: &HeapConfig) -> Self {
         assert!(range.start.start().is_multiple_of(VIRTUAL_QUANTUM_SIZE));
         assert!(range.end.start().is_multiple_of(VIRTUAL_QUANTUM_SIZE));
         let byte_size = range.end.start() - range.start.start();
         let quantum_count = (byte_size) / VIRTUAL_QUANTUM_SIZE;
-        let ret = QuantumStorage {
+        assert!(quantum_count <= 1 << QUANTUM_ID_BITS);
+        let shard_quanta = Self::shard_quanta(quantum_count, config);
+        let top_level = Self::top_level(quantum_count, config);
+        let mut shards = Vec::new_in(sys.allocator());
+        let mut start = 0;
+        while start < quantum_count {
+            let len = shard_quanta.min(quantum_count - start);
+            let transfer_capacity = config.transfer_buffer_capacity.unwrap_or((len / 2).max(1));
+            let levels = top_level.min(len.ilog2()) as usize + 1;
+            let shard = Shard {
+                available_quanta: BuddyTower::new(len, levels, sys.allocator()),
+                released_quanta: std::array::from_fn(|_| {
+                    BuddyTower::new(len, levels, sys.allocator())
+                }),
+                transfer_buffer: Mutex::new(Vec::with_capacity_in(
+                    transfer_capacity,
+                    sys.allocator(),
+                )),
+            };
+            let mut i = 0;
+            while i < len {
+                let remaining_quanta = len - i;
+                let level = i
+                    .trailing_zeros()
+                    .min(remaining_quanta.ilog2())
+                    .min(levels as u32 - 1);
+                shard.available_quanta.insert(i, level);
+                i += 1 << level;
+            }
+            debug_assert!(i == len);
+            shards.push(shard);
+            start += len;
+        }
+        QuantumStorage {
             quantum_base: AtomicUsize::new(range.start.start()),
-            available_quanta: BuddyTower::new(quantum_count, sys.allocator()),
-            released_quanta: BuddyTower::new(quantum_count, sys.allocator()),
-            transfer_buffer: Mutex::new(Vec::with_capacity_in(quantum_count / 2, sys.allocator())),
+            shards,
+            shard_quanta,
+            next_home: AtomicUsize::new(0),
+            alloc_rounds: config.quantum_alloc_rounds,
+            search_budget: config.quantum_search_budget,
+            targeted_flush_limit: config.targeted_flush_limit,
             sys,
-        };
-        let mut i = 0;
-        while i < quantum_count {
-            let remaining_quanta = quantum_count - i;
-            let level = i.trailing_zeros().min(remaining_quanta.ilog2());
-            ret.available_quanta.insert(i, level);
-            i += 1 << level;
         }
-        debug_assert!(i == quantum_count);
-        ret
     }
 }
+
+/// Moves all quanta in `released` to `tb`, passing it to `full` whenever it runs out of space.
+/// Returns false if there were none.
+fn drain_released<A: Allocator>(
+    released: &BuddyTower<A>,
+    tb: &mut Vec<u64, A>,
+    mut full: impl FnMut(&mut Vec<u64, A>),
+) -> bool {
+    let levels = released.levels();
+    assert!(levels <= (1 << TRANSFER_BUFFER_LEVEL_BITS));
+    let mut found = false;
+    for level in 0..levels {
+        for quantum in released.drain_level(level) {
+            if tb.len() == tb.capacity() {
+                full(tb);
+            }
+            tb.push(((level as u64) << QUANTUM_ID_BITS) | quantum as u64);
+            found = true;
+        }
+    }
+    found
+}
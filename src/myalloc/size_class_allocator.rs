@@ -0,0 +1,390 @@
+use crate::{
+    myalloc::LocalCommon,
+    util::{align_down_const, unsafe_assert, vaddr_unchecked, PAGE_SIZE},
+    GlobalData, SystemInterface,
+};
+use log::trace;
+use std::{
+    alloc::{Allocator, Layout},
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    ptr::{self, NonNull},
+    sync::atomic::{
+        fence, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
+};
+use x86_64::structures::paging::PhysFrame;
+
+/// An alternative to the [SmallAllocator](super::small_allocator::SmallAllocator) which reuses freed memory.
+///
+/// Each frame holds objects of a single size class, which are carved from the start of the frame.
+/// Objects freed by the owning handle go onto a free list of the frame, objects freed by other handles onto a second, atomic list.
+/// Frames remain owned until their handle is dropped or gives up their class, after which they are released once their last object is freed.
+pub struct SizeClassAllocator<S: SystemInterface, G: Deref<Target = GlobalData<S>>> {
+    /// allocated through [SystemInterface::allocator] on first use.
+    table: Option<NonNull<ClassTable>>,
+    _p: PhantomData<fn() -> G>,
+}
+
+struct ClassTable {
+    classes: [ClassLists; CLASS_COUNT],
+    /// the class whose frames were given up last, see [HeapConfig::size_class_limit](crate::HeapConfig::size_class_limit).
+    given_up: usize,
+}
+
+// the table is only accessed through the owning handle.
+unsafe impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> Send
+    for SizeClassAllocator<S, G>
+{
+}
+
+#[derive(Clone, Copy)]
+struct ClassLists {
+    /// frames that may have space left, the first one is allocated from.
+    available: *mut ClassFooter,
+    /// frames that had no space left when last allocated from.
+    full: *mut ClassFooter,
+}
+
+struct ClassFooter {
+    /// number of live objects, plus one while the frame is owned.
+    count: AtomicUsize,
+    /// objects freed by other handles.
+    remote_free: AtomicUsize,
+    /// address of the owning [ClassTable] or 0.
+    owner: AtomicUsize,
+    /// set when the frame is claimed.
+    class: u32,
+    // the remaining fields are only accessed by the owner.
+    local_free: usize,
+    bump: usize,
+    prev: *mut ClassFooter,
+    next: *mut ClassFooter,
+    full: bool,
+}
+
+/// classes are multiples of 16 up to 128, then four per doubling up to PAGE_SIZE / 2,
+/// the largest small size [HeapConfig](crate::HeapConfig) permits.
+const CLASS_COUNT: usize = class_index(PAGE_SIZE / 2) + 2;
+/// holds a single object at the start of each frame, for alignments no other class provides.
+const FRAME_ALIGNED_CLASS: usize = CLASS_COUNT - 1;
+
+#[inline]
+const fn class_index(size: usize) -> usize {
+    unsafe_assert!(size > 0);
+    if size <= 128 {
+        (size - 1) / 16
+    } else {
+        let k = (size - 1).ilog2();
+        let sub = (size - 1 - (1 << k)) >> (k - 2);
+        8 + (k as usize - 7) * 4 + sub
+    }
+}
+
+#[inline]
+fn class_size(index: usize) -> usize {
+    if index == FRAME_ALIGNED_CLASS {
+        PAGE_SIZE / 2
+    } else if index < 8 {
+        (index + 1) * 16
+    } else {
+        let k = (index - 8) / 4 + 7;
+        let sub = (index - 8) % 4;
+        (1 << k) + ((sub + 1) << (k - 2))
+    }
+}
+
+impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> Drop for SizeClassAllocator<S, G> {
+    fn drop(&mut self) {
+        assert!(self.table.is_none());
+    }
+}
+
+impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> SizeClassAllocator<S, G> {
+    #[inline]
+    pub const fn new() -> Self {
+        SizeClassAllocator {
+            table: None,
+            _p: PhantomData,
+        }
+    }
+
+    pub fn deinit(&mut self, common: &mut LocalCommon<S, G>) {
+        let Some(table) = self.table.take() else {
+            return;
+        };
+        for lists in unsafe { table.read() }.classes {
+            for mut frame in [lists.available, lists.full] {
+                while !frame.is_null() {
+                    unsafe {
+                        let next = (*frame).next;
+                        Self::disown(common, frame);
+                        frame = next;
+                    }
+                }
+            }
+        }
+        unsafe {
+            common
+                .global
+                .sys
+                .allocator()
+                .deallocate(table.cast(), Layout::new::<ClassTable>())
+        };
+    }
+
+    /// layout size must be in range 1..PAGE_SIZE / 2
+    #[inline]
+    pub fn alloc(&mut self, common: &mut LocalCommon<S, G>, layout: Layout) -> Option<NonNull<u8>> {
+        // the class of a multiple of the alignment is a multiple of the alignment as well.
+        let mut class = class_index(layout.size().next_multiple_of(layout.align()));
+        // frames in the direct map are not aligned any further, see [placed_size](crate::placed_size).
+        unsafe_assert!(layout.align() <= PAGE_SIZE);
+        if std::hint::unlikely(class >= FRAME_ALIGNED_CLASS) {
+            class = FRAME_ALIGNED_CLASS;
+        }
+        let size = class_size(class);
+        if std::hint::unlikely(self.table.is_none()) {
+            self.init_table(common);
+        }
+        let table = self.table.unwrap();
+        let owner = table.addr().get();
+        loop {
+            let lists = unsafe { &mut (*table.as_ptr()).classes[class] };
+            let frame = lists.available;
+            if std::hint::unlikely(frame.is_null()) {
+                Self::refill(common, unsafe { &mut *table.as_ptr() }, class, owner)?;
+                continue;
+            }
+            unsafe {
+                if let Some(object) = pop_object(frame, size) {
+                    (*frame).count.fetch_add(1, Relaxed);
+                    return Some(object);
+                }
+                let remote = (*frame).remote_free.swap(0, Acquire);
+                if remote != 0 {
+                    (*frame).local_free = remote;
+                    continue;
+                }
+                unlink(&mut lists.available, frame);
+                push_front(&mut lists.full, frame);
+                (*frame).full = true;
+            }
+        }
+    }
+
+    /// ptr must have been allocated by a [SizeClassAllocator] of any handle
+    #[inline]
+    pub unsafe fn dealloc(&mut self, common: &mut LocalCommon<S, G>, ptr: *mut u8) {
+        let frame = find_footer(ptr.addr());
+        let owner = self.table.map_or(0, |t| t.addr().get());
+        if owner != 0 && (*frame).owner.load(Relaxed) == owner {
+            ptr.cast::<usize>().write((*frame).local_free);
+            (*frame).local_free = ptr.addr();
+            let old_count = (*frame).count.fetch_sub(1, Release);
+            let table = &mut *self.table.unwrap().as_ptr();
+            let class = (*frame).class as usize;
+            if (*frame).full {
+                (*frame).full = false;
+                if table.classes[class].available.is_null() {
+                    Self::limit_classes(common, table, class);
+                }
+                let lists = &mut table.classes[class];
+                unlink(&mut lists.full, frame);
+                push_front(&mut lists.available, frame);
+            } else if old_count == 2 && table.classes[class].available != frame {
+                // only keep the frame currently allocated from if it is empty.
+                unlink(&mut table.classes[class].available, frame);
+                fence(Acquire);
+                Self::release_frame(common, frame);
+            }
+        } else {
+            let mut head = (*frame).remote_free.load(Relaxed);
+            loop {
+                ptr.cast::<usize>().write(head);
+                match (*frame)
+                    .remote_free
+                    .compare_exchange_weak(head, ptr.addr(), Release, Relaxed)
+                {
+                    Ok(_) => break,
+                    Err(x) => head = x,
+                }
+            }
+            if (*frame).count.fetch_sub(1, Release) == 1 {
+                fence(Acquire);
+                Self::release_frame(common, frame);
+            }
+        }
+    }
+
+    #[cold]
+    fn init_table(&mut self, common: &mut LocalCommon<S, G>) {
+        let empty = ClassLists {
+            available: ptr::null_mut(),
+            full: ptr::null_mut(),
+        };
+        let table = common
+            .global
+            .sys
+            .allocator()
+            .allocate(Layout::new::<ClassTable>())
+            .unwrap_or_else(|_| std::alloc::handle_alloc_error(Layout::new::<ClassTable>()))
+            .cast::<ClassTable>();
+        unsafe {
+            table.write(ClassTable {
+                classes: [empty; CLASS_COUNT],
+                given_up: 0,
+            })
+        };
+        self.table = Some(table);
+    }
+
+    /// Makes a frame available, preferably one that received remote frees.
+    #[cold]
+    fn refill(
+        common: &mut LocalCommon<S, G>,
+        table: &mut ClassTable,
+        class: usize,
+        owner: usize,
+    ) -> Option<()> {
+        Self::limit_classes(common, table, class);
+        let lists = &mut table.classes[class];
+        let mut frame = lists.full;
+        while !frame.is_null() {
+            unsafe {
+                let next = (*frame).next;
+                if (*frame).count.load(Acquire) == 1 {
+                    unlink(&mut lists.full, frame);
+                    Self::release_frame(common, frame);
+                } else if (*frame).remote_free.load(Relaxed) != 0 {
+                    unlink(&mut lists.full, frame);
+                    push_front(&mut lists.available, frame);
+                    (*frame).full = false;
+                    return Some(());
+                }
+                frame = next;
+            }
+        }
+        let frame = common
+            .available_frames
+            .pop_with_refill(&common.global.available_frames, 1)?;
+        trace!("claiming frame {frame:?} for size class {class}");
+        let start = common.global.sys.vaddr(frame.start_address()).as_u64() as usize;
+        let footer = find_footer(start);
+        unsafe {
+            footer.write(ClassFooter {
+                count: AtomicUsize::new(1),
+                remote_free: AtomicUsize::new(0),
+                owner: AtomicUsize::new(owner),
+                class: class as u32,
+                local_free: 0,
+                bump: start,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                full: false,
+            });
+            push_front(&mut lists.available, footer);
+        }
+        Some(())
+    }
+
+    /// Gives up the frames another class allocates from, if opening `class` would exceed
+    /// [HeapConfig::size_class_limit](crate::HeapConfig::size_class_limit).
+    /// Classes are given up in turn, their full frames stay owned.
+    #[cold]
+    fn limit_classes(common: &mut LocalCommon<S, G>, table: &mut ClassTable, class: usize) {
+        let open = table
+            .classes
+            .iter()
+            .filter(|lists| !lists.available.is_null())
+            .count();
+        if open < common.global.config.size_class_limit {
+            return;
+        }
+        loop {
+            table.given_up = (table.given_up + 1) % CLASS_COUNT;
+            if table.given_up != class && !table.classes[table.given_up].available.is_null() {
+                break;
+            }
+        }
+        let lists = &mut table.classes[table.given_up];
+        while !lists.available.is_null() {
+            let frame = lists.available;
+            unsafe {
+                unlink(&mut lists.available, frame);
+                Self::disown(common, frame);
+            }
+        }
+    }
+
+    /// From now on, frees into the frame take the remote path, and the last of them releases it.
+    unsafe fn disown(common: &mut LocalCommon<S, G>, frame: *mut ClassFooter) {
+        (*frame).owner.store(0, Relaxed);
+        if (*frame).count.fetch_sub(1, Release) == 1 {
+            fence(Acquire);
+            Self::release_frame(common, frame);
+        }
+    }
+
+    unsafe fn release_frame(common: &mut LocalCommon<S, G>, footer: *const ClassFooter) {
+        let page = align_down_const::<PAGE_SIZE>(footer.addr());
+        let vaddr = unsafe { vaddr_unchecked(page) };
+        let paddr = common.global.sys.paddr(vaddr);
+        let frame = unsafe { PhysFrame::from_start_address_unchecked(paddr) };
+        trace!("releasing frame {frame:?}");
+        unsafe { common.available_frames.push(frame).unwrap() };
+        common.release_extra_frames();
+    }
+}
+
+/// The size of the class of the small object at `ptr`.
+#[cfg(feature = "sizeless_free")]
+#[inline]
+pub unsafe fn allocation_size(ptr: NonNull<u8>) -> usize {
+    class_size((*find_footer(ptr.addr().get())).class as usize)
+}
+
+#[inline]
+unsafe fn pop_object(frame: *mut ClassFooter, size: usize) -> Option<NonNull<u8>> {
+    let object = (*frame).local_free;
+    if object != 0 {
+        (*frame).local_free = ptr::with_exposed_provenance::<usize>(object).read();
+        return Some(NonNull::new_unchecked(object as *mut u8));
+    }
+    let object = (*frame).bump;
+    if object + size <= frame.addr() {
+        (*frame).bump = object + size;
+        return Some(NonNull::new_unchecked(object as *mut u8));
+    }
+    None
+}
+
+unsafe fn push_front(head: &mut *mut ClassFooter, frame: *mut ClassFooter) {
+    (*frame).prev = ptr::null_mut();
+    (*frame).next = *head;
+    if !head.is_null() {
+        (**head).prev = frame;
+    }
+    *head = frame;
+}
+
+unsafe fn unlink(head: &mut *mut ClassFooter, frame: *mut ClassFooter) {
+    let (prev, next) = ((*frame).prev, (*frame).next);
+    if prev.is_null() {
+        *head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+}
+
+#[inline]
+fn find_footer(addr: usize) -> *mut ClassFooter {
+    let max_addr = addr | (PAGE_SIZE - 1);
+    let address = max_addr - (mem::size_of::<ClassFooter>() - 1);
+    address as *mut ClassFooter
+}
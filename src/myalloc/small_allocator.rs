@@ -57,6 +57,8 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> SmallAllocator<S, G>
     pub fn alloc(&mut self, common: &mut LocalCommon<S, G>, layout: Layout) -> Option<NonNull<u8>> {
         unsafe_assert!(layout.size() > 0);
         unsafe_assert!(layout.size() <= PAGE_SIZE / 2);
+        // frames in the direct map are not aligned any further, see [placed_size](crate::placed_size).
+        unsafe_assert!(layout.align() <= PAGE_SIZE);
         let mut claimed = false;
         loop {
             let new_bump =
@@ -64,7 +66,6 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> SmallAllocator<S, G>
             let bump_limit = align_down_const::<PAGE_SIZE>(self.bump);
             if std::hint::unlikely(wrapping_less_than(new_bump, bump_limit)) {
                 unsafe_assert!(!claimed);
-                assert!(layout.align() <= PAGE_SIZE / 2);
                 self.claim_frame(common);
                 claimed = true;
                 continue;
@@ -78,7 +79,7 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> SmallAllocator<S, G>
     }
 
     #[inline]
-    pub unsafe fn dealloc(common: &mut LocalCommon<S, G>, ptr: *mut u8) {
+    pub unsafe fn dealloc(&mut self, common: &mut LocalCommon<S, G>, ptr: *mut u8) {
         Self::decrement_counter(common, find_footer(ptr.addr()));
     }
 
@@ -98,9 +99,7 @@ impl<S: SystemInterface, G: Deref<Target = GlobalData<S>>> SmallAllocator<S, G>
         let frame = unsafe { PhysFrame::from_start_address_unchecked(paddr) };
         trace!("releasing frame {frame:?}");
         unsafe { common.available_frames.push(frame).unwrap() };
-        common
-            .available_frames
-            .release_extra_to_vec(&common.global.available_frames);
+        common.release_extra_frames();
     }
 
     fn claim_frame(&mut self, common: &mut LocalCommon<S, G>) -> Option<()> {
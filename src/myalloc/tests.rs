@@ -0,0 +1,684 @@
+//! Tests against the [PageTableSimulation], which only supports small and large allocations.
+//! Medium allocations are tested against the [LinuxSystemInterface](crate::LinuxSystemInterface) where it is available.
+
+use crate::util::{page_from_addr, vaddr_unchecked, PAGE_SIZE};
+use crate::{
+    GlobalData, HeapPageSize, LocalData, PageTableSimulation, SimulationEvent, SystemInterface,
+    TestAlloc,
+};
+use std::alloc::Layout;
+use std::collections::HashSet;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use x86_64::structures::paging::{Page, PhysFrame};
+
+const MIB: usize = 1 << 20;
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+/// The heap pages covering `size` bytes at `ptr`.
+fn pages(ptr: NonNull<u8>, size: usize) -> impl Iterator<Item = Page<HeapPageSize>> {
+    let start = ptr.as_ptr().addr() / PAGE_SIZE * PAGE_SIZE;
+    (start..ptr.as_ptr().addr() + size)
+        .step_by(PAGE_SIZE)
+        .map(|addr| unsafe { page_from_addr(vaddr_unchecked(addr)) })
+}
+
+#[test]
+fn frame_pool_grows_to_limit() {
+    let sim = PageTableSimulation::new(128 * MIB);
+    let global = GlobalData::builder(sim.interface(), 16 * MIB, 1 << 32)
+        .max_physical_size(48 * MIB)
+        .build();
+    let mut local = LocalData::new(0, &global);
+    let mut allocations = Vec::new();
+    while let Some(ptr) = unsafe { local.alloc(layout(8 * MIB)) } {
+        allocations.push(ptr);
+    }
+    assert_eq!(allocations.len(), 6);
+    for ptr in allocations {
+        unsafe { local.dealloc(ptr, 8 * MIB) };
+    }
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 48 * MIB / PAGE_SIZE);
+    // the failed allocation left nothing behind, the whole limit can be allocated again.
+    let mut local = LocalData::new(1, &global);
+    let ptr = unsafe { local.alloc(layout(48 * MIB)) }.unwrap();
+    unsafe { local.dealloc(ptr, 48 * MIB) };
+}
+
+#[test]
+fn dealloc_large_unmaps_every_page() {
+    let sim = PageTableSimulation::new(64 * MIB);
+    let global = GlobalData::new(sim.interface(), 32 * MIB, 1 << 30);
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(20 * MIB)) }.unwrap();
+    assert!(pages(ptr, 20 * MIB).all(|page| sim.translate(page).is_some()));
+    unsafe { local.dealloc(ptr, 20 * MIB) };
+    assert!(pages(ptr, 20 * MIB).all(|page| sim.translate(page).is_none()));
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 32 * MIB / PAGE_SIZE);
+}
+
+#[test]
+fn realloc_large_in_place_and_moved() {
+    let sim = PageTableSimulation::new(128 * MIB);
+    let global = GlobalData::new(sim.interface(), 64 * MIB, 1 << 30);
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(30 * MIB)) }.unwrap();
+    let start = ptr.as_ptr().addr();
+    sim.take_events();
+    // shrinking within the same buddy block unmaps the tail and flushes it.
+    let shrunk = unsafe { local.realloc(ptr, 30 * MIB, layout(20 * MIB)) }.unwrap();
+    assert_eq!(shrunk, ptr);
+    assert!(sim.take_events().contains(&SimulationEvent::TlbFlushRange {
+        start: start + 20 * MIB,
+        end: start + 30 * MIB,
+    }));
+    assert!(pages(ptr, 20 * MIB).all(|page| sim.translate(page).is_some()));
+    assert!(pages(ptr, 30 * MIB)
+        .skip(20 * MIB / PAGE_SIZE)
+        .all(|page| sim.translate(page).is_none()));
+    // growing within it maps the tail again.
+    let grown = unsafe { local.realloc(shrunk, 20 * MIB, layout(30 * MIB)) }.unwrap();
+    assert_eq!(grown, ptr);
+    assert!(pages(ptr, 30 * MIB).all(|page| sim.translate(page).is_some()));
+    // growing beyond it moves the frames to a larger block.
+    let moved = unsafe { local.realloc(grown, 30 * MIB, layout(40 * MIB)) }.unwrap();
+    assert_ne!(moved, ptr);
+    assert!(pages(ptr, 30 * MIB).all(|page| sim.translate(page).is_none()));
+    let old_frames: Vec<_> = sim
+        .events()
+        .iter()
+        .filter_map(|event| match event {
+            SimulationEvent::Unmap { frame, .. } => Some(*frame),
+            _ => None,
+        })
+        .collect();
+    assert!(pages(moved, 30 * MIB).all(|page| old_frames.contains(&sim.translate(page).unwrap())));
+    assert!(pages(moved, 40 * MIB).all(|page| sim.translate(page).is_some()));
+    unsafe { local.dealloc(moved, 40 * MIB) };
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 64 * MIB / PAGE_SIZE);
+}
+
+/// Objects freed by another handle are reused once the frame of their class runs out.
+/// Opening a class beyond the limit gives up the frame of another, which its last free releases.
+#[cfg(all(feature = "size_classes", feature = "linux_system_interface"))]
+#[test]
+fn size_classes_reuse_remote_frees() {
+    let sys = crate::LinuxSystemInterface::new(64 * MIB);
+    let global = GlobalData::builder(sys, 32 * MIB, 1 << 30)
+        .size_class_limit(1)
+        .build();
+    let size = global.config().max_small_size;
+    let mut owner = LocalData::new(0, &global);
+    let mut other = LocalData::new(1, &global);
+    let page = |ptr: NonNull<u8>| ptr.addr().get() / PAGE_SIZE;
+    // the footer takes up the end of the frame.
+    let count = PAGE_SIZE / size - 1;
+    let first: HashSet<_> = (0..count)
+        .map(|_| unsafe { owner.alloc(layout(size)) }.unwrap())
+        .collect();
+    let frame = page(*first.iter().next().unwrap());
+    assert!(first.iter().all(|&ptr| page(ptr) == frame));
+    for &ptr in &first {
+        unsafe { other.dealloc(ptr, size) };
+    }
+    let second: HashSet<_> = (0..count)
+        .map(|_| unsafe { owner.alloc(layout(size)) }.unwrap())
+        .collect();
+    assert_eq!(first, second);
+    let last = unsafe { owner.alloc(layout(size)) }.unwrap();
+    unsafe { owner.dealloc(last, size) };
+
+    let tiny = unsafe { owner.alloc(layout(16)) }.unwrap();
+    let reopened = unsafe { owner.alloc(layout(size)) }.unwrap();
+    assert_ne!(page(reopened), page(last));
+    for ptr in second.into_iter().chain([reopened]) {
+        unsafe { owner.dealloc(ptr, size) };
+    }
+    unsafe { owner.dealloc(tiny, 16) };
+    drop((owner, other));
+    assert_eq!(global.available_frames.pooled(), 32 * MIB / PAGE_SIZE);
+}
+
+/// The space of a freed object is reused by the next allocation that fits, instead of bump allocating.
+#[cfg(feature = "linux_system_interface")]
+#[test]
+fn medium_extents_are_reused() {
+    let sys = crate::LinuxSystemInterface::new(256 * MIB);
+    let global = GlobalData::new(sys, 128 * MIB, 1 << 34);
+    let size = global.config().max_medium_size / 2;
+    let mut local = LocalData::new(0, &global);
+    let [first, middle, last] = [(); 3].map(|_| unsafe { local.alloc(layout(size)) }.unwrap());
+    for round in 0..100 {
+        unsafe {
+            middle.as_ptr().write_bytes(round, size);
+            local.dealloc(middle, size);
+        }
+        assert_eq!(unsafe { local.alloc(layout(size)) }, Some(middle));
+    }
+    for ptr in [first, middle, last] {
+        unsafe { local.dealloc(ptr, size) };
+    }
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 128 * MIB / PAGE_SIZE);
+}
+
+/// An object aligned entirely below the page holding the bump pointer drops that page's bump count,
+/// so the page is unmapped once its objects are freed.
+#[cfg(feature = "linux_system_interface")]
+#[test]
+fn medium_alignment_padding_releases_bump_page() {
+    let sys = crate::LinuxSystemInterface::new(256 * MIB);
+    let global = GlobalData::new(sys, 128 * MIB, 1 << 34);
+    let small = global.config().max_small_size + 8;
+    let mut local = LocalData::new(0, &global);
+    let first = unsafe { local.alloc(layout(small)) }.unwrap();
+    // too large to fit above the start of the page, so it is placed at the start of the page below.
+    let aligned =
+        Layout::from_size_align(first.as_ptr().addr() % PAGE_SIZE + 8, PAGE_SIZE).unwrap();
+    let below = unsafe { local.alloc(aligned) }.unwrap();
+    assert!(
+        below.as_ptr().addr() + aligned.size() <= first.as_ptr().addr() / PAGE_SIZE * PAGE_SIZE
+    );
+    unsafe {
+        local.dealloc(first, small);
+        local.dealloc(below, aligned.size());
+    }
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 128 * MIB / PAGE_SIZE);
+}
+
+/// Layouts aligned to more than a page are placed by the larger of size and alignment in every tier,
+/// and freed and resized through the same size.
+#[cfg(feature = "linux_system_interface")]
+#[test]
+fn over_aligned_layouts() {
+    use crate::LocalAllocator;
+    use std::alloc::Allocator;
+    use std::cell::RefCell;
+    let sys = crate::LinuxSystemInterface::new(512 * MIB);
+    let global = GlobalData::new(sys, 256 * MIB, 1 << 34);
+    let local = RefCell::new(LocalData::new(0, &global));
+    let allocator = LocalAllocator::new(&local);
+    let max_medium = global.config().max_medium_size;
+    for (size, align) in [
+        (64, 2 * PAGE_SIZE),
+        (64, 4 * MIB),
+        (64, 64 * MIB),
+        (max_medium, 32 * MIB),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = allocator.allocate(layout).unwrap().cast::<u8>();
+        assert!(ptr.as_ptr().addr().is_multiple_of(align));
+        unsafe { ptr.as_ptr().write_bytes(1, size) };
+        let grown = Layout::from_size_align(2 * size, align).unwrap();
+        let ptr = unsafe { allocator.grow(ptr, layout, grown) }
+            .unwrap()
+            .cast::<u8>();
+        assert!(ptr.as_ptr().addr().is_multiple_of(align));
+        assert_eq!(unsafe { *ptr.as_ptr().add(size - 1) }, 1);
+        unsafe { allocator.deallocate(ptr, grown) };
+    }
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 256 * MIB / PAGE_SIZE);
+}
+
+/// The largest arena the heap accepts can be reserved, as its alignment is below its size.
+#[cfg(feature = "linux_system_interface")]
+#[test]
+fn largest_arena_initialises() {
+    let sys = crate::LinuxSystemInterface::new(64 * MIB);
+    let global = GlobalData::new(sys, 32 * MIB, crate::util::lower_half_end(4) / 2);
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(32 * MIB)) }.unwrap();
+    unsafe { local.dealloc(ptr, 32 * MIB) };
+}
+
+/// Each limit is checked before the heap is built.
+#[test]
+fn heap_config_validation() {
+    use crate::{HeapConfig, SimulatedSystemInterface};
+    let valid = HeapConfig::DEFAULT;
+    assert_eq!(valid.validate::<SimulatedSystemInterface>(), Ok(()));
+    let invalid = [
+        (
+            "max_small_size",
+            HeapConfig {
+                max_small_size: PAGE_SIZE / 2,
+                ..valid
+            },
+        ),
+        (
+            "max_medium_size",
+            HeapConfig {
+                max_medium_size: valid.max_small_size,
+                ..valid
+            },
+        ),
+        (
+            "frame_cache_limit",
+            HeapConfig {
+                frame_cache_limit: 0,
+                ..valid
+            },
+        ),
+        (
+            "frame_refill_size",
+            HeapConfig {
+                frame_refill_size: 0,
+                ..valid
+            },
+        ),
+        (
+            "quantum_alloc_rounds",
+            HeapConfig {
+                quantum_alloc_rounds: 0,
+                ..valid
+            },
+        ),
+        (
+            "max_physical_size",
+            HeapConfig {
+                max_physical_size: Some(PAGE_SIZE + 1),
+                ..valid
+            },
+        ),
+        (
+            "quantum_shards",
+            HeapConfig {
+                quantum_shards: 0,
+                ..valid
+            },
+        ),
+        (
+            "transfer_buffer_capacity",
+            HeapConfig {
+                transfer_buffer_capacity: Some(0),
+                ..valid
+            },
+        ),
+    ];
+    for (field, config) in invalid {
+        let error = config.validate::<SimulatedSystemInterface>().unwrap_err();
+        assert!(error.starts_with(field), "{field}: {error}");
+    }
+}
+
+#[test]
+#[should_panic(expected = "invalid heap configuration: max_small_size")]
+fn builder_rejects_invalid_config() {
+    let sim = PageTableSimulation::new(64 * MIB);
+    GlobalData::builder(sim.interface(), 32 * MIB, 1 << 30)
+        .max_small_size(8)
+        .build();
+}
+
+/// With 4KiB pages, large allocations are only rounded up to 4KiB and mapped through level 1 tables.
+#[cfg(feature = "small_pages")]
+#[test]
+fn small_pages_round_large_allocations_to_4kib() {
+    use x86_64::structures::paging::{PageSize, Size4KiB};
+    assert_eq!(PAGE_SIZE as u64, Size4KiB::SIZE);
+    let sim = PageTableSimulation::new(64 * MIB);
+    let global = GlobalData::new(sim.interface(), 32 * MIB, 1 << 30);
+    let size = global.config().max_medium_size + PAGE_SIZE + 1;
+    let mut local = LocalData::new(0, &global);
+    sim.take_events();
+    let ptr = unsafe { local.alloc(layout(size)) }.unwrap();
+    let mapped = sim
+        .take_events()
+        .iter()
+        .filter(|event| matches!(event, SimulationEvent::Map { .. }))
+        .count();
+    assert_eq!(mapped, size.div_ceil(PAGE_SIZE));
+    assert!(pages(ptr, size).all(|page| sim.translate(page).is_some()));
+    unsafe { local.dealloc(ptr, size) };
+    assert!(pages(ptr, size).all(|page| sim.translate(page).is_none()));
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 32 * MIB / PAGE_SIZE);
+}
+
+/// The GiB-aligned part of a large allocation is mapped by a giant page, which is unmapped as a whole when freed.
+#[test]
+fn giant_pages_map_aligned_gib() {
+    const GIB: usize = 1 << 30;
+    let sim = PageTableSimulation::new(2 * GIB + 64 * MIB);
+    let global = GlobalData::builder(sim.interface(), 32 * MIB, 1 << 36)
+        .giant_frames(1)
+        .build();
+    let mut local = LocalData::new(0, &global);
+    let size = GIB + 4 * MIB;
+    for _ in 0..2 {
+        sim.take_events();
+        let ptr = unsafe { local.alloc(layout(size)) }.unwrap();
+        assert!(global.giant_pages.is_mapped(ptr.addr().get()));
+        let events = sim.take_events();
+        let giant = |event: &&SimulationEvent| matches!(event, SimulationEvent::MapGiant { .. });
+        let small = |event: &&SimulationEvent| matches!(event, SimulationEvent::Map { .. });
+        assert_eq!(events.iter().filter(giant).count(), 1);
+        assert_eq!(events.iter().filter(small).count(), 4 * MIB / PAGE_SIZE);
+        unsafe { local.dealloc(ptr, size) };
+        assert!(!global.giant_pages.is_mapped(ptr.addr().get()));
+        let events = sim.take_events();
+        let giant = |event: &&SimulationEvent| matches!(event, SimulationEvent::UnmapGiant { .. });
+        let small = |event: &&SimulationEvent| matches!(event, SimulationEvent::Unmap { .. });
+        assert_eq!(events.iter().filter(giant).count(), 1);
+        assert_eq!(events.iter().filter(small).count(), 4 * MIB / PAGE_SIZE);
+    }
+}
+
+/// Giant pages are never split, so shrinking an allocation to end inside one moves it.
+#[cfg(feature = "linux_system_interface")]
+#[test]
+fn shrinking_into_a_giant_page_copies() {
+    const GIB: usize = 1 << 30;
+    let sys = crate::LinuxSystemInterface::new(2 * GIB + 64 * MIB);
+    let global = GlobalData::builder(sys, 32 * MIB, 1 << 36)
+        .giant_frames(1)
+        .build();
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(GIB)) }.unwrap();
+    assert!(global.giant_pages.is_mapped(ptr.addr().get()));
+    let new_size = global.config().max_medium_size.next_multiple_of(PAGE_SIZE);
+    for i in (0..new_size).step_by(4096) {
+        unsafe { ptr.as_ptr().add(i).write(i as u8 ^ 0x5a) };
+    }
+    let moved = unsafe { local.realloc(ptr, GIB, layout(new_size)) }.unwrap();
+    assert_ne!(moved, ptr);
+    assert!(!global.giant_pages.is_mapped(ptr.addr().get()));
+    for i in (0..new_size).step_by(4096) {
+        assert_eq!(unsafe { moved.as_ptr().add(i).read() }, i as u8 ^ 0x5a);
+    }
+    unsafe { local.dealloc(moved, new_size) };
+    // the giant frame went back to the pool.
+    let ptr = unsafe { local.alloc(layout(GIB)) }.unwrap();
+    assert!(global.giant_pages.is_mapped(ptr.addr().get()));
+    unsafe { local.dealloc(ptr, GIB) };
+}
+
+/// Arenas too large for four levels are placed above 128TiB and walked through a level 5 table.
+#[test]
+fn five_level_arena_beyond_128tib() {
+    // the page directories of the arena take up 256MiB.
+    let sim = PageTableSimulation::with_paging_levels(512 * MIB, 5);
+    let virt_size = crate::util::lower_half_end(4) / 2 + 1024 * MIB;
+    let global = GlobalData::new(sim.interface(), 32 * MIB, virt_size);
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(20 * MIB)) }.unwrap();
+    assert!(ptr.as_ptr().addr() >= crate::util::lower_half_end(4));
+    assert!(pages(ptr, 20 * MIB).all(|page| sim.translate(page).is_some()));
+    unsafe { local.dealloc(ptr, 20 * MIB) };
+    assert!(pages(ptr, 20 * MIB).all(|page| sim.translate(page).is_none()));
+}
+
+/// Ranges crossing a leaf table boundary map and unmap every page in order.
+#[test]
+fn map_range_crosses_leaf_tables() {
+    use x86_64::structures::paging::{page::PageRangeInclusive, Size2MiB};
+    const ENTRIES: usize = 512;
+    let sim = PageTableSimulation::new(ENTRIES * 2 * PAGE_SIZE);
+    let sys = sim.interface();
+    let virt_size = 4 << 30;
+    let virt = sys.allocate_virtual(Layout::from_size_align(virt_size, 1 << 30).unwrap());
+    let virt = virt.as_u64() as usize;
+    let page_2mib = |addr: usize| unsafe { page_from_addr::<Size2MiB>(vaddr_unchecked(addr)) };
+    unsafe {
+        sys.prepare_page_table(PageRangeInclusive {
+            start: page_2mib(virt),
+            end: page_2mib(virt + virt_size - (2 << 20)),
+        })
+    };
+    let start = virt + (ENTRIES - 3) * PAGE_SIZE;
+    let count = ENTRIES + 8;
+    let page = |i: usize| unsafe { page_from_addr(vaddr_unchecked(start + i * PAGE_SIZE)) };
+    let frames: Vec<PhysFrame<HeapPageSize>> = (0..count)
+        .map(|_| {
+            let addr = sys.allocate_physical(layout(PAGE_SIZE).align_to(PAGE_SIZE).unwrap());
+            PhysFrame::from_start_address(addr).unwrap()
+        })
+        .rev()
+        .collect();
+    sim.take_events();
+    let mut next = frames.iter().copied();
+    unsafe { sys.map_range(page(0), count, || next.next().unwrap()) };
+    assert!(next.next().is_none());
+    let mapped: Vec<_> = sim
+        .take_events()
+        .into_iter()
+        .filter_map(|event| match event {
+            SimulationEvent::Map { page, frame } => Some((page, frame)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        mapped,
+        (0..count).map(|i| (page(i), frames[i])).collect::<Vec<_>>()
+    );
+    for (i, &frame) in frames.iter().enumerate() {
+        assert_eq!(sim.translate(page(i)), Some(frame));
+    }
+    assert_eq!(sim.translate(page(count)), None);
+    let before = unsafe { page_from_addr(vaddr_unchecked(start - PAGE_SIZE)) };
+    assert_eq!(sim.translate(before), None);
+
+    let mut released = Vec::new();
+    unsafe { sys.unmap_range(page(0), count, |frame| released.push(frame)) };
+    assert_eq!(released, frames);
+    assert!((0..count).all(|i| sim.translate(page(i)).is_none()));
+}
+
+/// A quantum is only handed out again once every TLB was flushed after it was unmapped.
+/// Flushes by the rest of the system count as well, so the heap needs fewer of its own.
+#[test]
+fn quanta_are_recycled_after_flush() {
+    let mut own_flushes = Vec::new();
+    for external_flushes in [false, true] {
+        let sim = PageTableSimulation::new(128 * MIB);
+        let global = GlobalData::builder(sim.interface(), 64 * MIB, 1 << 30)
+            .targeted_flush_limit(0)
+            .build();
+        let mut local = LocalData::new(0, &global);
+        let mut stale = HashSet::new();
+        let mut flushes = 0;
+        for i in 0..1000 {
+            let ptr = unsafe { local.alloc(layout(20 * MIB)) }.unwrap();
+            unsafe { local.dealloc(ptr, 20 * MIB) };
+            for event in sim.take_events() {
+                match event {
+                    SimulationEvent::Map { page, .. } => assert!(!stale.contains(&page)),
+                    SimulationEvent::Unmap { page, .. } => {
+                        stale.insert(page);
+                    }
+                    SimulationEvent::GlobalTlbFlush => {
+                        stale.clear();
+                        flushes += 1;
+                    }
+                    SimulationEvent::TlbFlushRange { start, end } => stale.retain(|page| {
+                        !(start..end).contains(&(page.start_address().as_u64() as usize))
+                    }),
+                    _ => {}
+                }
+            }
+            if external_flushes && i % 4 == 0 {
+                sim.flush_everywhere();
+                stale.clear();
+            }
+        }
+        own_flushes.push(flushes);
+    }
+    assert!(own_flushes[0] > 0);
+    assert!(own_flushes[1] < own_flushes[0] / 4, "{own_flushes:?}");
+}
+
+/// Released quanta are flushed by range if the system supports it, otherwise with a single global flush.
+#[test]
+fn recycling_coalesces_flushes_without_range_flush() {
+    for range_flush in [true, false] {
+        let mut sim = PageTableSimulation::new(128 * MIB);
+        sim.set_range_flush(range_flush);
+        let global = GlobalData::new(sim.interface(), 64 * MIB, 128 * MIB);
+        let mut local = LocalData::new(0, &global);
+        let size = global.config().max_medium_size;
+        let allocations: Vec<_> = (0..8)
+            .map(|_| unsafe { local.alloc(layout(size)) }.unwrap())
+            .collect();
+        for ptr in allocations {
+            unsafe { local.dealloc(ptr, size) };
+        }
+        sim.take_events();
+        // every quantum is released, so this one is recycled.
+        let ptr = unsafe { local.alloc(layout(size)) }.unwrap();
+        unsafe { local.dealloc(ptr, size) };
+        let flushes: Vec<_> = sim
+            .take_events()
+            .into_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    SimulationEvent::GlobalTlbFlush | SimulationEvent::TlbFlushRange { .. }
+                )
+            })
+            .collect();
+        if range_flush {
+            assert_eq!(flushes.len(), 4);
+            assert!(flushes
+                .iter()
+                .all(|event| matches!(event, SimulationEvent::TlbFlushRange { .. })));
+        } else {
+            assert_eq!(flushes, [SimulationEvent::GlobalTlbFlush]);
+        }
+    }
+}
+
+#[test]
+fn release_memory_frees_pooled_frames() {
+    let sim = PageTableSimulation::new(128 * MIB);
+    let global = GlobalData::builder(sim.interface(), 16 * MIB, 1 << 32)
+        .max_physical_size(32 * MIB)
+        .build();
+    let mut local = LocalData::new(0, &global);
+    let ptr = unsafe { local.alloc(layout(24 * MIB)) }.unwrap();
+    unsafe { local.dealloc(ptr, 24 * MIB) };
+    drop(local);
+    let pooled = global.available_frames.pooled() * PAGE_SIZE;
+    assert!(pooled >= 24 * MIB);
+    sim.take_events();
+    assert_eq!(global.release_memory(4 * MIB), pooled - 4 * MIB);
+    assert_eq!(global.available_frames.pooled(), 4 * MIB / PAGE_SIZE);
+    let freed: usize = sim
+        .take_events()
+        .iter()
+        .map(|event| match event {
+            SimulationEvent::FreePhysical { size, .. } => *size,
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(freed, pooled - 4 * MIB);
+    // released frames are claimed again on demand.
+    let mut local = LocalData::new(1, &global);
+    let ptr = unsafe { local.alloc(layout(24 * MIB)) }.unwrap();
+    unsafe { local.dealloc(ptr, 24 * MIB) };
+}
+
+/// Handles take frames from the node they run on, and from the other nodes once it runs out.
+#[cfg(feature = "numa")]
+#[test]
+fn frames_come_from_the_current_node() {
+    let mut sim = PageTableSimulation::new(256 * MIB);
+    sim.set_numa_nodes(2);
+    let size = crate::HeapConfig::DEFAULT
+        .max_medium_size
+        .next_multiple_of(PAGE_SIZE);
+    // each node holds the frames of three allocations.
+    let global = GlobalData::new(sim.interface(), 6 * size, 1 << 32);
+    let node =
+        |page| sim.translate(page).unwrap().start_address().as_u64() as usize / PAGE_SIZE % 2;
+    sim.set_current_node(1);
+    let mut local = LocalData::new(0, &global);
+    let first = unsafe { local.alloc(layout(size)) }.unwrap();
+    assert!(pages(first, size).all(|page| node(page) == 1));
+    let more = [(); 3].map(|_| unsafe { local.alloc(layout(size)) }.unwrap());
+    assert!(more
+        .iter()
+        .any(|&ptr| pages(ptr, size).any(|page| node(page) == 0)));
+    for ptr in more.into_iter().chain([first]) {
+        unsafe { local.dealloc(ptr, size) };
+    }
+    drop(local);
+    assert_eq!(global.available_frames.pooled(), 6 * size / PAGE_SIZE);
+}
+
+/// Frames taken from the pool by concurrent threads are never handed out twice, and all of them come back.
+#[test]
+fn frame_pool_concurrent_take_and_put_back() {
+    let sim = PageTableSimulation::new(256 * MIB);
+    let sys = sim.interface();
+    // few enough frames that no list fills half of a frame, where the holder of a frame is noted.
+    let count = 64;
+    let global = GlobalData::new(sys, count * PAGE_SIZE, 1 << 30);
+    let pool = &global.available_frames;
+    let holder = |frame: PhysFrame<HeapPageSize>| unsafe {
+        &*(sys.vaddr(frame.start_address()) + (PAGE_SIZE / 2) as u64).as_ptr::<AtomicUsize>()
+    };
+    std::thread::scope(|scope| {
+        for thread in 1..=4 {
+            scope.spawn(move || {
+                for round in 0..20000 {
+                    let mut frames = Vec::new();
+                    pool.take(0, 1 + (thread + round) % 5, |frame| frames.push(frame))
+                        .unwrap();
+                    for &frame in &frames {
+                        assert_eq!(holder(frame).swap(thread, Relaxed), 0);
+                    }
+                    for &frame in &frames {
+                        assert_eq!(holder(frame).swap(0, Relaxed), thread);
+                    }
+                    pool.put_back(|| frames.pop());
+                }
+            });
+        }
+    });
+    assert_eq!(pool.pooled(), count);
+    let mut frames = HashSet::new();
+    pool.take(0, count, |frame| assert!(frames.insert(frame)))
+        .unwrap();
+}
+
+/// Handles take quanta from other shards once their own is full, and recycle quanta released there.
+#[test]
+fn quantum_shards_steal_and_recycle() {
+    let sim = PageTableSimulation::new(192 * MIB);
+    let global = GlobalData::builder(sim.interface(), 128 * MIB, 256 * MIB)
+        .quantum_shards(2)
+        .build();
+    let mut first = LocalData::new(0, &global);
+    let mut second = LocalData::new(1, &global);
+    // each allocation takes one quantum, and each shard holds eight.
+    let size = 8 * MIB;
+    let stolen: Vec<_> = (0..12)
+        .map(|_| unsafe { first.alloc(layout(size)) }.unwrap())
+        .collect();
+    let own: Vec<_> = (0..4)
+        .map(|_| unsafe { second.alloc(layout(size)) }.unwrap())
+        .collect();
+    let distinct: HashSet<_> = stolen.iter().chain(&own).collect();
+    assert_eq!(distinct.len(), 16);
+    assert!(unsafe { first.alloc(layout(size)) }.is_none());
+    // the quantum is released in the other shard, so only recycling it there makes it available again.
+    unsafe { first.dealloc(stolen[8], size) };
+    let recycled = unsafe { first.alloc(layout(size)) }.unwrap();
+    assert_eq!(recycled, stolen[8]);
+    unsafe {
+        for ptr in stolen {
+            first.dealloc(ptr, size);
+        }
+        for ptr in own {
+            second.dealloc(ptr, size);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use libc::{
+    MAP_ANONYMOUS, MAP_HUGETLB, MAP_HUGE_1GB, MAP_HUGE_2MB, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTable, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::util::{arena_hint, lower_half_end, vaddr_unchecked, PAGE_SIZE, VIRTUAL_QUANTUM_BITS};
+use crate::SystemInterface;
+use std::alloc::{Layout, System};
+use std::ops::Range;
+use std::ptr;
+use std::sync::atomic::{
+    AtomicPtr, AtomicU64, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+use std::sync::Mutex;
+
+pub const PHYS_OFFSET: u64 = 0x0000400000000000;
+#[derive(Clone, Copy)]
+pub struct OsvSystemInterface;
+unsafe impl SystemInterface for OsvSystemInterface {
+    fn allocate_virtual(self, layout: Layout) -> x86_64::VirtAddr {
+        let align = layout.align().max(1 << VIRTUAL_QUANTUM_BITS);
+        // returned range is at least quantum aligned
+        let virt_pages_exclusive = alloc_mmap_at::<Size2MiB>(
+            arena_hint(layout.size()),
+            (layout.size() + align) >> 21,
+            false,
+        );
+        let virt_pages_inclusive =
+            Page::range_inclusive(virt_pages_exclusive.start, virt_pages_exclusive.end - 1);
+
+        let start = virt_pages_inclusive
+            .start
+            .start_address()
+            .as_u64()
+            .next_multiple_of(align as u64) as usize;
+        assert!(start + layout.size() <= lower_half_end(self.paging_levels()));
+        // give back the parts only reserved for alignment.
+        let reserved_start = virt_pages_exclusive.start.start_address().as_u64() as usize;
+        let reserved_end = virt_pages_exclusive.end.start_address().as_u64() as usize;
+        unsafe {
+            libc::munmap(
+                ptr::with_exposed_provenance_mut(reserved_start),
+                start - reserved_start,
+            );
+            libc::munmap(
+                ptr::with_exposed_provenance_mut(start + layout.size()),
+                reserved_end - start - layout.size(),
+            );
+            vaddr_unchecked(start)
+        }
+    }
+
+    fn allocate_physical(self, layout: Layout) -> x86_64::PhysAddr {
+        assert_eq!(layout.size(), layout.align());
+        match layout.size() as u64 {
+            Size4KiB::SIZE => allocate_frame::<Size4KiB>(),
+            Size2MiB::SIZE => allocate_frame::<Size2MiB>(),
+            Size1GiB::SIZE => allocate_frame::<Size1GiB>(),
+            _ => unimplemented!(),
+        }
+    }
+
+    /// Unmaps the anonymous mapping the frame was allocated through.
+    /// The linear map at [PHYS_OFFSET], which [vaddr](SystemInterface::vaddr) points into, covers all physical memory and stays.
+    unsafe fn free_physical(self, addr: PhysAddr, layout: Layout) {
+        let virt = frame_mapping(addr).swap(0, Relaxed);
+        assert!(
+            virt != 0,
+            "frame was not allocated through allocate_physical"
+        );
+        if libc::munmap(ptr::with_exposed_provenance_mut(virt), layout.size()) != 0 {
+            panic!("munmap failed: {:?}", std::io::Error::last_os_error());
+        }
+    }
+
+    fn global_tlb_flush(self) {
+        // flushes are serialised, so the one completing the epoch after `e` started after `e` was read.
+        let _flushing = TLB_FLUSH.lock().unwrap();
+        unsafe {
+            libc::syscall(0x1000);
+        }
+        TLB_FLUSH_EPOCH.fetch_add(1, Release);
+    }
+
+    /// Only counts flushes requested through [Self::global_tlb_flush].
+    fn tlb_flush_epoch(self) -> u64 {
+        TLB_FLUSH_EPOCH.load(Acquire)
+    }
+
+    /// `invlpg` only reaches the current core, so it is only used if there is no other.
+    /// Other cores can only be reached by the global flush.
+    fn flush_tlb_range(self, range: Range<usize>) {
+        let pages = range.len().div_ceil(PAGE_SIZE);
+        if pages > RANGE_FLUSH_LIMIT || online_cpus() > 1 {
+            self.global_tlb_flush();
+            return;
+        }
+        for page in range.step_by(PAGE_SIZE) {
+            x86_64::instructions::tlb::flush(unsafe { vaddr_unchecked(page) });
+        }
+    }
+
+    fn has_range_flush(self) -> bool {
+        online_cpus() == 1
+    }
+
+    fn vaddr(self, addr: x86_64::PhysAddr) -> x86_64::VirtAddr {
+        VirtAddr::new(addr.as_u64() + PHYS_OFFSET)
+    }
+
+    fn paddr(self, addr: x86_64::VirtAddr) -> x86_64::PhysAddr {
+        PhysAddr::new(addr.as_u64() - PHYS_OFFSET)
+    }
+
+    fn allocator(self) -> Self::Alloc {
+        System
+    }
+
+    type Alloc = System;
+}
+
+static TLB_FLUSH: Mutex<()> = Mutex::new(());
+static TLB_FLUSH_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// pages invalidated one by one before flushing everything is cheaper, as on Linux.
+const RANGE_FLUSH_LIMIT: usize = 33;
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+fn online_cpus() -> usize {
+    let mut cpus = ONLINE_CPUS.load(Relaxed);
+    if cpus == 0 {
+        cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+        ONLINE_CPUS.store(cpus, Relaxed);
+    }
+    cpus
+}
+
+/// physical memory covered by [FRAME_MAPPINGS].
+const MAX_PHYSICAL: u64 = 1 << 40;
+
+/// the address of the anonymous mapping each frame was allocated through, by physical 4KiB frame number.
+/// Reserved on first use without going through the process allocator, only the touched parts are backed.
+static FRAME_MAPPINGS: AtomicPtr<AtomicUsize> = AtomicPtr::new(ptr::null_mut());
+
+fn frame_mapping(phys: PhysAddr) -> &'static AtomicUsize {
+    assert!(
+        phys.as_u64() < MAX_PHYSICAL,
+        "frame beyond the mapping table"
+    );
+    let mut table = FRAME_MAPPINGS.load(Acquire);
+    if table.is_null() {
+        let pages =
+            (MAX_PHYSICAL / Size4KiB::SIZE) as usize * size_of::<usize>() / Size4KiB::SIZE as usize;
+        let new = alloc_mmap::<Size4KiB>(pages, true)
+            .start
+            .start_address()
+            .as_mut_ptr();
+        table = match FRAME_MAPPINGS.compare_exchange(ptr::null_mut(), new, AcqRel, Acquire) {
+            Ok(_) => new,
+            Err(other) => {
+                unsafe { libc::munmap(new.cast(), pages * Size4KiB::SIZE as usize) };
+                other
+            }
+        };
+    }
+    unsafe { &*table.add((phys.as_u64() / Size4KiB::SIZE) as usize) }
+}
+
+fn allocate_frame<P: PageSize>() -> PhysAddr
+where
+    for<'a> OffsetPageTable<'a>: Mapper<P>,
+{
+    let virt = alloc_mmap::<P>(1, false);
+    unsafe {
+        virt.start
+            .start_address()
+            .as_mut_ptr::<usize>()
+            .write_volatile(0);
+    }
+    let phys = unsafe { page_table() }
+        .translate_page(virt.start)
+        .unwrap()
+        .start_address();
+    frame_mapping(phys).store(virt.start.start_address().as_u64() as usize, Relaxed);
+    phys
+}
+
+pub fn alloc_mmap<P: PageSize>(count: usize, zeroed: bool) -> PageRange<P> {
+    alloc_mmap_at(0, count, zeroed)
+}
+
+/// Like [alloc_mmap], but passes `hint` as the preferred address.
+pub fn alloc_mmap_at<P: PageSize>(hint: usize, count: usize, zeroed: bool) -> PageRange<P> {
+    // from osv/libs/mman.cc
+    const MAP_UNINITIALIZED: i32 = 0x4000000;
+    let page_size_flags = match P::SIZE {
+        Size4KiB::SIZE => 0,
+        Size2MiB::SIZE => MAP_HUGETLB | MAP_HUGE_2MB,
+        Size1GiB::SIZE => MAP_HUGETLB | MAP_HUGE_1GB,
+        _ => panic!("bad page size {}", P::DEBUG_STR),
+    };
+    let init_flags = if zeroed { 0 } else { MAP_UNINITIALIZED };
+    let p = unsafe {
+        libc::mmap(
+            ptr::with_exposed_provenance_mut(hint),
+            count * P::SIZE as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS | page_size_flags | init_flags,
+            -1,
+            0,
+        ) as *mut u8
+    };
+    if (p as i64) == -1 {
+        panic!("mmap failed: {:?}", std::io::Error::last_os_error());
+    }
+
+    assert!(!p.is_null());
+    let p = Page::<P>::from_start_address(VirtAddr::from_ptr(p)).unwrap();
+    Page::range(p, p + count as u64)
+}
+
+unsafe fn page_table<'a>() -> OffsetPageTable<'a> {
+    OffsetPageTable::new(
+        &mut *OsvSystemInterface
+            .vaddr(Cr3::read().0.start_address())
+            .as_mut_ptr::<PageTable>(),
+        VirtAddr::new(PHYS_OFFSET),
+    )
+}
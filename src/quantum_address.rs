@@ -1,8 +1,6 @@
 use std::{fmt, num::NonZeroUsize};
 
-use x86_64::VirtAddr;
-
-use crate::util::{align_down_const, VIRTUAL_QUANTUM_SIZE};
+use crate::util::{align_down_const, is_canonical, VIRTUAL_QUANTUM_SIZE};
 
 /// The starting address of a virtual quantum
 #[derive(Clone, Copy)]
@@ -21,8 +19,8 @@ impl QuantumAddress {
 
     pub fn from_start(addr: usize) -> Self {
         if cfg!(debug_assertions) {
-            VirtAddr::new(addr as u64);
-            VirtAddr::new((addr + VIRTUAL_QUANTUM_SIZE) as u64);
+            assert!(is_canonical(addr));
+            assert!(is_canonical(addr + VIRTUAL_QUANTUM_SIZE));
             assert!(addr.is_multiple_of(VIRTUAL_QUANTUM_SIZE));
         }
         QuantumAddress(NonZeroUsize::new(addr).unwrap())
@@ -0,0 +1,350 @@
+use std::alloc::{self, Layout, System};
+use std::cell::Cell;
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use x86_64::structures::paging::{Page, PageSize, PhysFrame, Size1GiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::system_interface::{
+    direct_access_map, direct_access_map_giant, direct_access_map_range, direct_access_unmap,
+    direct_access_unmap_giant, direct_access_unmap_range, find_leaf_entry, LEAF_FLAGS,
+};
+use crate::util::{
+    arena_hint, lower_half_end, page_from_addr, vaddr_unchecked, HeapPageSize, PAGE_SIZE,
+    VIRTUAL_QUANTUM_SIZE,
+};
+use crate::SystemInterface;
+
+/// Start of the simulated virtual address space, far away from anything the process maps itself.
+const VIRTUAL_BASE: usize = 1 << 45;
+
+/// A simulated machine with its own physical memory and a four- or five-level page table.
+///
+/// Physical memory is an owned buffer, which also serves as the direct map.
+/// The page table lives in that buffer and is manipulated by the same `direct_access_*` functions used on real hardware.
+/// Virtual addresses handed out by [SystemInterface::allocate_virtual] are only translated in software, they are not accessible.
+/// Hence, only allocations that are never accessed through the heap mapping (small and large allocations) can be made against it.
+pub struct PageTableSimulation {
+    memory: NonNull<u8>,
+    memory_layout: Layout,
+    root: PhysFrame<Size4KiB>,
+    paging_levels: u32,
+    numa_nodes: usize,
+    range_flush: bool,
+    next_physical: AtomicUsize,
+    next_virtual: AtomicUsize,
+    flush_epoch: AtomicU64,
+    events: Mutex<Vec<SimulationEvent>>,
+}
+
+thread_local! {
+    /// the simulated NUMA node of the calling thread.
+    static CURRENT_NODE: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl Send for PageTableSimulation {}
+unsafe impl Sync for PageTableSimulation {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimulationEvent {
+    Map {
+        page: Page<HeapPageSize>,
+        frame: PhysFrame<HeapPageSize>,
+    },
+    Unmap {
+        page: Page<HeapPageSize>,
+        frame: PhysFrame<HeapPageSize>,
+    },
+    MapGiant {
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+    },
+    UnmapGiant {
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+    },
+    FreePhysical {
+        addr: PhysAddr,
+        size: usize,
+    },
+    GlobalTlbFlush,
+    TlbFlushRange {
+        start: usize,
+        end: usize,
+    },
+}
+
+#[derive(Clone, Copy)]
+pub struct SimulatedSystemInterface<'a> {
+    simulation: &'a PageTableSimulation,
+}
+
+impl PageTableSimulation {
+    pub fn new(physical_size: usize) -> Self {
+        Self::with_paging_levels(physical_size, 4)
+    }
+
+    /// With 5 levels, arenas beyond 128TiB can be simulated on machines without five-level paging.
+    pub fn with_paging_levels(physical_size: usize, paging_levels: u32) -> Self {
+        assert!(paging_levels == 4 || paging_levels == 5);
+        assert!(physical_size.is_multiple_of(PAGE_SIZE));
+        let memory_layout = Layout::from_size_align(physical_size, PAGE_SIZE).unwrap();
+        let memory = NonNull::new(unsafe { alloc::alloc_zeroed(memory_layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(memory_layout));
+        let mut ret = PageTableSimulation {
+            memory,
+            memory_layout,
+            root: PhysFrame::containing_address(PhysAddr::zero()),
+            paging_levels,
+            numa_nodes: 1,
+            range_flush: true,
+            // address 0 is never handed out
+            next_physical: AtomicUsize::new(Size4KiB::SIZE as usize),
+            next_virtual: AtomicUsize::new(VIRTUAL_BASE),
+            flush_epoch: AtomicU64::new(0),
+            events: Mutex::new(Vec::new()),
+        };
+        ret.root = PhysFrame::from_start_address(ret.interface().allocate_physical(
+            Layout::from_size_align(Size4KiB::SIZE as usize, Size4KiB::SIZE as usize).unwrap(),
+        ))
+        .unwrap();
+        ret
+    }
+
+    pub fn interface(&self) -> SimulatedSystemInterface<'_> {
+        SimulatedSystemInterface { simulation: self }
+    }
+
+    /// Walks the page table in software.
+    pub fn translate(&self, page: Page<HeapPageSize>) -> Option<PhysFrame<HeapPageSize>> {
+        let entry = unsafe { &*find_leaf_entry(self.interface(), page.start_address(), false)? };
+        if entry.is_unused() {
+            return None;
+        }
+        assert!(entry.flags().contains(LEAF_FLAGS));
+        Some(PhysFrame::from_start_address(entry.addr()).unwrap())
+    }
+
+    /// Interleaves physical memory across `nodes` NUMA nodes, one heap page at a time.
+    pub fn set_numa_nodes(&mut self, nodes: usize) {
+        assert!(nodes > 0);
+        self.numa_nodes = nodes;
+    }
+
+    /// Sets the node reported by [SystemInterface::current_node] on the calling thread.
+    pub fn set_current_node(&self, node: usize) {
+        assert!(node < self.numa_nodes);
+        CURRENT_NODE.set(node);
+    }
+
+    /// Makes [SystemInterface::flush_tlb_range] flush everything, like a machine that cannot target other cores.
+    pub fn set_range_flush(&mut self, supported: bool) {
+        self.range_flush = supported;
+    }
+
+    pub fn events(&self) -> Vec<SimulationEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Simulates a complete flush of all TLBs by some other part of the system.
+    pub fn flush_everywhere(&self) {
+        self.flush_epoch.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn take_events(&self) -> Vec<SimulationEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+
+    fn record(&self, event: SimulationEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl Drop for PageTableSimulation {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.memory.as_ptr(), self.memory_layout) };
+    }
+}
+
+unsafe impl SystemInterface for SimulatedSystemInterface<'_> {
+    fn allocate_virtual(self, layout: Layout) -> VirtAddr {
+        let mut start = 0;
+        self.simulation
+            .next_virtual
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                // like mmap, only place the arena above 128TiB if the hint asks for it.
+                start = next
+                    .max(arena_hint(layout.size()))
+                    .next_multiple_of(layout.align().max(VIRTUAL_QUANTUM_SIZE));
+                Some(start + layout.size())
+            })
+            .unwrap();
+        assert!(start + layout.size() <= lower_half_end(self.simulation.paging_levels));
+        unsafe { vaddr_unchecked(start) }
+    }
+
+    fn allocate_physical(self, layout: Layout) -> PhysAddr {
+        let mut start = 0;
+        self.simulation
+            .next_physical
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                start = next.next_multiple_of(layout.align());
+                Some(start + layout.size())
+            })
+            .unwrap();
+        assert!(
+            start + layout.size() <= self.simulation.memory_layout.size(),
+            "out of simulated physical memory"
+        );
+        PhysAddr::new(start as u64)
+    }
+
+    /// Simulated memory is never reused.
+    unsafe fn free_physical(self, addr: PhysAddr, layout: Layout) {
+        self.simulation.record(SimulationEvent::FreePhysical {
+            addr,
+            size: layout.size(),
+        });
+    }
+
+    fn numa_node_count(self) -> usize {
+        self.simulation.numa_nodes
+    }
+
+    fn frame_node(self, addr: PhysAddr) -> usize {
+        (addr.as_u64() as usize / PAGE_SIZE) % self.simulation.numa_nodes
+    }
+
+    fn current_node(self) -> usize {
+        CURRENT_NODE.get()
+    }
+
+    fn global_tlb_flush(self) {
+        self.simulation.record(SimulationEvent::GlobalTlbFlush);
+        self.simulation.flush_everywhere();
+    }
+
+    fn tlb_flush_epoch(self) -> u64 {
+        self.simulation.flush_epoch.load(Ordering::Relaxed)
+    }
+
+    fn flush_tlb_range(self, range: Range<usize>) {
+        if !self.simulation.range_flush {
+            self.global_tlb_flush();
+            return;
+        }
+        self.simulation.record(SimulationEvent::TlbFlushRange {
+            start: range.start,
+            end: range.end,
+        });
+    }
+
+    fn has_range_flush(self) -> bool {
+        self.simulation.range_flush
+    }
+
+    fn vaddr(self, addr: PhysAddr) -> VirtAddr {
+        assert!((addr.as_u64() as usize) < self.simulation.memory_layout.size());
+        VirtAddr::from_ptr(unsafe { self.simulation.memory.as_ptr().add(addr.as_u64() as usize) })
+    }
+
+    fn paddr(self, addr: VirtAddr) -> PhysAddr {
+        let offset = (addr.as_u64() as usize).wrapping_sub(self.simulation.memory.as_ptr().addr());
+        assert!(offset < self.simulation.memory_layout.size());
+        PhysAddr::new(offset as u64)
+    }
+
+    unsafe fn map(self, page: Page<HeapPageSize>, frame: PhysFrame<HeapPageSize>) {
+        assert!(self.simulation.translate(page).is_none());
+        direct_access_map(self, page, frame);
+        self.simulation.record(SimulationEvent::Map { page, frame });
+    }
+
+    unsafe fn unmap(self, page: Page<HeapPageSize>) -> PhysFrame<HeapPageSize> {
+        assert!(self.simulation.translate(page).is_some());
+        let frame = direct_access_unmap(self, page);
+        self.simulation
+            .record(SimulationEvent::Unmap { page, frame });
+        frame
+    }
+
+    unsafe fn map_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        mut frames: impl FnMut() -> PhysFrame<HeapPageSize>,
+    ) {
+        let start_addr = start.start_address().as_u64() as usize;
+        let page = |i: usize| page_from_addr(vaddr_unchecked(start_addr + i * PAGE_SIZE));
+        for i in 0..count {
+            assert!(self.simulation.translate(page(i)).is_none());
+        }
+        let mut i = 0;
+        direct_access_map_range(self, start, count, || {
+            let frame = frames();
+            self.simulation.record(SimulationEvent::Map {
+                page: page(i),
+                frame,
+            });
+            i += 1;
+            frame
+        });
+    }
+
+    unsafe fn unmap_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        mut release: impl FnMut(PhysFrame<HeapPageSize>),
+    ) {
+        let start_addr = start.start_address().as_u64() as usize;
+        let page = |i: usize| page_from_addr(vaddr_unchecked(start_addr + i * PAGE_SIZE));
+        let mut i = 0;
+        direct_access_unmap_range(self, start, count, |frame| {
+            self.simulation.record(SimulationEvent::Unmap {
+                page: page(i),
+                frame,
+            });
+            i += 1;
+            release(frame);
+        });
+    }
+
+    unsafe fn map_giant(
+        self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let table = direct_access_map_giant(self, page, frame);
+        self.simulation
+            .record(SimulationEvent::MapGiant { page, frame });
+        table
+    }
+
+    unsafe fn unmap_giant(
+        self,
+        page: Page<Size1GiB>,
+        table: Option<PhysFrame<Size4KiB>>,
+    ) -> PhysFrame<Size1GiB> {
+        let frame = direct_access_unmap_giant(self, page, table);
+        self.simulation
+            .record(SimulationEvent::UnmapGiant { page, frame });
+        frame
+    }
+
+    fn page_table_root(self) -> PhysFrame<Size4KiB> {
+        self.simulation.root
+    }
+
+    fn paging_levels(self) -> u32 {
+        self.simulation.paging_levels
+    }
+
+    fn allocator(self) -> Self::Alloc {
+        System
+    }
+
+    type Alloc = System;
+}
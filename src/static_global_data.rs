@@ -0,0 +1,41 @@
+use crate::myalloc::GlobalData;
+use crate::osv_system_interface::OsvSystemInterface;
+use std::cell::SyncUnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static GLOBAL_INIT_STATE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct GlobalGlobal;
+
+impl Deref for GlobalGlobal {
+    type Target = GlobalData<OsvSystemInterface>;
+
+    fn deref(&self) -> &Self::Target {
+        assert!(is_initialized());
+        unsafe { (*GLOBAL.get()).assume_init_ref() }
+    }
+}
+
+static GLOBAL: SyncUnsafeCell<MaybeUninit<GlobalData<OsvSystemInterface>>> =
+    SyncUnsafeCell::new(MaybeUninit::uninit());
+
+pub fn is_initialized() -> bool {
+    GLOBAL_INIT_STATE.load(Ordering::Acquire) == 2
+}
+
+/// Initializes the global data unless it has already been initialized.
+/// Must not be called concurrently.
+pub unsafe fn init(physical_size: u64, virtual_size: u64) {
+    if GLOBAL_INIT_STATE.load(Ordering::Relaxed) == 2 {
+        return;
+    }
+    assert_eq!(GLOBAL_INIT_STATE.swap(1, Ordering::Relaxed), 0);
+    (*GLOBAL.get()).write(GlobalData::new(
+        OsvSystemInterface,
+        physical_size as usize,
+        virtual_size as usize,
+    ));
+    GLOBAL_INIT_STATE.store(2, Ordering::Release);
+}
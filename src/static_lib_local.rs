@@ -0,0 +1,78 @@
+use crate::myalloc::{placed_size, LocalData};
+use crate::osv_system_interface::OsvSystemInterface;
+use crate::static_global_data::{self, GlobalGlobal};
+use crate::TestAlloc;
+use static_assertions::const_assert;
+use std::alloc::Layout;
+use std::mem::{align_of, size_of};
+use std::ptr::{self, NonNull};
+
+type CLocalData = LocalData<OsvSystemInterface, GlobalGlobal>;
+
+/// Mirrors `VirtualAllocHandle` in `virtual_alloc_local.h`.
+/// The handle is owned by C code, which may move it around via `memcpy`.
+/// This is fine, as [LocalData] holds no pointers into itself.
+#[repr(C)]
+pub struct VirtualAllocHandle {
+    _private: [u64; 11],
+}
+
+const_assert!(size_of::<CLocalData>() <= size_of::<VirtualAllocHandle>());
+const_assert!(align_of::<CLocalData>() <= align_of::<VirtualAllocHandle>());
+
+impl VirtualAllocHandle {
+    unsafe fn local<'a>(this: *mut Self) -> &'a mut CLocalData {
+        &mut *this.cast::<CLocalData>()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn virtual_alloc_init_global(physical_size: u64, virtual_size: u64) {
+    static_global_data::init(physical_size, virtual_size);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn virtual_alloc_init_handle(
+    dst: *mut VirtualAllocHandle,
+    seed: u64,
+) -> bool {
+    if !static_global_data::is_initialized() {
+        return false;
+    }
+    dst.cast::<CLocalData>()
+        .write(CLocalData::new(seed, GlobalGlobal));
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn virtual_alloc_destroy_handle(local: *mut VirtualAllocHandle) {
+    ptr::drop_in_place(local.cast::<CLocalData>());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn virtual_alloc_alloc(
+    local: *mut VirtualAllocHandle,
+    size: u64,
+    align: u64,
+) -> *mut libc::c_void {
+    VirtualAllocHandle::local(local)
+        .alloc(Layout::from_size_align_unchecked(
+            size as usize,
+            align as usize,
+        ))
+        .map_or(Default::default(), NonNull::as_ptr) as *mut libc::c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn virtual_alloc_free(
+    local: *mut VirtualAllocHandle,
+    size: u64,
+    align: u64,
+    ptr: *mut libc::c_void,
+) {
+    let placed = placed_size(Layout::from_size_align_unchecked(
+        size as usize,
+        align as usize,
+    ));
+    VirtualAllocHandle::local(local).dealloc(NonNull::new_unchecked(ptr as *mut u8), placed)
+}
@@ -0,0 +1,130 @@
+use crate::malloc;
+use crate::VirtualGlobalAlloc;
+use libc::{c_int, c_void, size_t, EINVAL, ENOMEM};
+use std::ffi::CStr;
+
+const DEFAULT_PHYSICAL_SIZE: usize = 1 << 30;
+const DEFAULT_VIRTUAL_SIZE: usize = 1 << 40;
+
+#[cfg(not(feature = "linux_preload"))]
+static ALLOC: VirtualGlobalAlloc<crate::osv_system_interface::OsvSystemInterface> = unsafe {
+    VirtualGlobalAlloc::new(|| {
+        crate::GlobalData::new(
+            crate::osv_system_interface::OsvSystemInterface,
+            physical_size(),
+            virtual_size(),
+        )
+    })
+};
+
+#[cfg(feature = "linux_preload")]
+static ALLOC: VirtualGlobalAlloc<crate::LinuxSystemInterface> =
+    unsafe { VirtualGlobalAlloc::new(crate::linux_preload::make_global) };
+
+pub fn physical_size() -> usize {
+    size_from_env(c"VIRTUAL_ALLOC_PHYSICAL_SIZE", DEFAULT_PHYSICAL_SIZE)
+}
+
+pub fn virtual_size() -> usize {
+    size_from_env(c"VIRTUAL_ALLOC_VIRTUAL_SIZE", DEFAULT_VIRTUAL_SIZE)
+}
+
+/// Reads a size in bytes from the environment without allocating.
+fn size_from_env(name: &CStr, default: usize) -> usize {
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    if value.is_null() {
+        return default;
+    }
+    let value = unsafe { CStr::from_ptr(value) };
+    match value.to_str().ok().and_then(|x| x.parse().ok()) {
+        Some(x) => x,
+        None => panic!("invalid value for {name:?}: {value:?}"),
+    }
+}
+
+fn set_errno(e: c_int) {
+    unsafe { *libc::__errno_location() = e };
+}
+
+fn check_null(ptr: *mut u8) -> *mut c_void {
+    if ptr.is_null() {
+        set_errno(ENOMEM);
+    }
+    ptr.cast()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    check_null(malloc::malloc(&ALLOC, size))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    check_null(malloc::calloc(&ALLOC, count, size))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    let ret = malloc::realloc(&ALLOC, ptr.cast(), size);
+    if size == 0 {
+        ret.cast()
+    } else {
+        check_null(ret)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    malloc::free(&ALLOC, ptr.cast());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_errno(EINVAL);
+        return std::ptr::null_mut();
+    }
+    check_null(malloc::malloc_aligned(&ALLOC, size, align))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    dst: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || !align.is_multiple_of(size_of::<*mut c_void>()) {
+        return EINVAL;
+    }
+    let ptr = malloc::malloc_aligned(&ALLOC, size, align);
+    if ptr.is_null() {
+        return ENOMEM;
+    }
+    *dst = ptr.cast();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    aligned_alloc(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void {
+    aligned_alloc(page_size(), size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    let page_size = page_size();
+    aligned_alloc(page_size, size.next_multiple_of(page_size))
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    malloc::usable_size(&ALLOC, ptr.cast())
+}
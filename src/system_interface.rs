@@ -2,97 +2,320 @@ use log::{debug, warn};
 use std::{
     alloc::{Allocator, Layout},
     mem::MaybeUninit,
+    ops::Range,
 };
 use x86_64::{
-    registers::control::Cr3,
+    registers::control::{Cr3, Cr4, Cr4Flags},
     structures::paging::{
         page::PageRangeInclusive, page_table::PageTableEntry, FrameAllocator, Page, PageSize,
-        PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+        PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+use crate::util::{align_down_const, vaddr_unchecked, HeapPageSize, PAGE_SIZE};
+
 /// # Safety
 /// Addresses must be non-zero
 pub unsafe trait SystemInterface: Sized + Copy {
     fn allocate_virtual(self, layout: Layout) -> VirtAddr;
     fn allocate_physical(self, layout: Layout) -> PhysAddr;
+    /// Returns memory obtained from [allocate_physical](Self::allocate_physical) with the same layout.
+    /// It must remain readable through [vaddr](Self::vaddr), with any contents,
+    /// as the frame pool may still read a stale link from a frame it released concurrently.
+    /// It is leaked by default.
+    unsafe fn free_physical(self, addr: PhysAddr, layout: Layout) {
+        let _ = (addr, layout);
+    }
+    /// Like [allocate_physical](Self::allocate_physical), preferring memory attached to NUMA node `node`.
+    /// The node is only a hint, it is ignored by default.
+    fn allocate_physical_on(self, layout: Layout, node: usize) -> PhysAddr {
+        let _ = node;
+        self.allocate_physical(layout)
+    }
+    /// Number of NUMA nodes, which are numbered from 0.
+    fn numa_node_count(self) -> usize {
+        1
+    }
+    /// The NUMA node the physical memory at `addr` is attached to.
+    fn frame_node(self, addr: PhysAddr) -> usize {
+        let _ = addr;
+        0
+    }
+    /// The NUMA node of the CPU the calling thread runs on.
+    fn current_node(self) -> usize {
+        0
+    }
     fn global_tlb_flush(self);
+    /// Number of times all TLBs have been flushed completely, for any reason.
+    /// Pages unmapped before reading epoch `e` are flushed everywhere once it reaches `e + 2`.
+    /// Interfaces that do not track flushes always report 0.
+    fn tlb_flush_epoch(self) -> u64 {
+        0
+    }
+    /// Invalidates the translations of the addresses in `range` on all cores.
+    /// Without a way to target them, everything is flushed.
+    fn flush_tlb_range(self, range: Range<usize>) {
+        let _ = range;
+        self.global_tlb_flush();
+    }
+    /// Whether [flush_tlb_range](Self::flush_tlb_range) is cheaper than a global flush.
+    /// If not, several ranges are flushed by a single global flush instead.
+    fn has_range_flush(self) -> bool {
+        false
+    }
     fn vaddr(self, addr: PhysAddr) -> VirtAddr;
     fn paddr(self, addr: VirtAddr) -> PhysAddr;
+    /// Prepares the tables down to level 2, regardless of the [HeapPageSize].
     unsafe fn prepare_page_table(self, range: PageRangeInclusive<Size2MiB>) {
         direct_access_prepare_page_table(self, range);
     }
 
-    unsafe fn map(self, page: Page<Size2MiB>, frame: PhysFrame<Size2MiB>) {
+    unsafe fn map(self, page: Page<HeapPageSize>, frame: PhysFrame<HeapPageSize>) {
         direct_access_map(self, page, frame);
     }
 
-    unsafe fn unmap(self, page: Page<Size2MiB>) -> PhysFrame<Size2MiB> {
+    unsafe fn unmap(self, page: Page<HeapPageSize>) -> PhysFrame<HeapPageSize> {
         direct_access_unmap(self, page)
     }
+
+    /// Maps `count` consecutive pages from `start` to the frames returned by `frames`.
+    unsafe fn map_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        frames: impl FnMut() -> PhysFrame<HeapPageSize>,
+    ) {
+        direct_access_map_range(self, start, count, frames);
+    }
+
+    /// Unmaps `count` consecutive pages from `start` and passes their frames to `release`.
+    unsafe fn unmap_range(
+        self,
+        start: Page<HeapPageSize>,
+        count: usize,
+        release: impl FnMut(PhysFrame<HeapPageSize>),
+    ) {
+        direct_access_unmap_range(self, start, count, release);
+    }
+
+    /// Maps a 1GiB page in place of the level 2 table covering it, which must not map any pages.
+    /// Returns the replaced table, which must be passed to [unmap_giant](Self::unmap_giant).
+    unsafe fn map_giant(
+        self,
+        page: Page<Size1GiB>,
+        frame: PhysFrame<Size1GiB>,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        direct_access_map_giant(self, page, frame)
+    }
+
+    unsafe fn unmap_giant(
+        self,
+        page: Page<Size1GiB>,
+        table: Option<PhysFrame<Size4KiB>>,
+    ) -> PhysFrame<Size1GiB> {
+        direct_access_unmap_giant(self, page, table)
+    }
+    /// The frame holding the top level table used by the `direct_access_*` functions.
+    fn page_table_root(self) -> PhysFrame<Size4KiB> {
+        Cr3::read().0
+    }
+    /// 5 if the root is a level 5 table, 4 otherwise.
+    fn paging_levels(self) -> u32 {
+        if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+            5
+        } else {
+            4
+        }
+    }
     fn trace_recycle_backoff(self) {}
     fn trace_recycle(self) {}
     fn allocator(self) -> Self::Alloc;
     type Alloc: Allocator + Clone;
 }
 
+/// Flags of the entries mapping heap pages.
+pub(crate) const LEAF_FLAGS: PageTableFlags = if HeapPageSize::SIZE == Size2MiB::SIZE {
+    PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::HUGE_PAGE)
+} else {
+    PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE)
+};
+
+unsafe fn table(sys: impl SystemInterface, frame: PhysFrame) -> *mut PageTableEntry {
+    sys.vaddr(frame.start_address())
+        .as_mut_ptr::<PageTableEntry>()
+}
+
+/// Allocates a zeroed table and points `entry` to it.
+unsafe fn create_table(sys: impl SystemInterface, entry: *mut PageTableEntry) {
+    let layout = Layout::from_size_align(Size4KiB::SIZE as usize, Size4KiB::SIZE as usize).unwrap();
+    let frame = sys.allocate_physical(layout);
+    sys.vaddr(frame)
+        .as_mut_ptr::<MaybeUninit<PageTable>>()
+        .write(MaybeUninit::zeroed());
+    (*entry).set_addr(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+/// Walks the levels above level 3 and returns the level 3 table covering `addr`.
+/// Returns None if a table on the way is missing, unless `create` is set.
+unsafe fn find_l3_table(
+    sys: impl SystemInterface,
+    addr: VirtAddr,
+    create: bool,
+) -> Option<*mut PageTableEntry> {
+    let mut l4 = table(sys, sys.page_table_root());
+    if sys.paging_levels() == 5 {
+        let l5_entry = l4.add((addr.as_u64() >> 48) as usize & 511);
+        if create && (*l5_entry).is_unused() {
+            create_table(sys, l5_entry);
+        }
+        l4 = table(sys, (*l5_entry).frame().ok()?);
+    }
+    let l4_entry = l4.add(addr.p4_index().into());
+    if create && (*l4_entry).is_unused() {
+        create_table(sys, l4_entry);
+    }
+    Some(table(sys, (*l4_entry).frame().ok()?))
+}
+
+/// Walks the page table down to the entry mapping the heap page at `addr`.
+/// Returns None if a table on the way is missing.
+/// With 4KiB pages, a missing level 1 table is allocated instead if `create` is set.
+/// Level 1 tables are never freed, the level 2 tables are set up by [direct_access_prepare_page_table].
+pub(crate) unsafe fn find_leaf_entry(
+    sys: impl SystemInterface,
+    addr: VirtAddr,
+    create: bool,
+) -> Option<*mut PageTableEntry> {
+    let l3 = find_l3_table(sys, addr, false)?;
+    let l2 = table(sys, l3.add(addr.p3_index().into()).read().frame().ok()?);
+    let l2_entry = l2.add(addr.p2_index().into());
+    if HeapPageSize::SIZE == Size2MiB::SIZE {
+        return Some(l2_entry);
+    }
+    if (*l2_entry).is_unused() {
+        if !create {
+            return None;
+        }
+        // a level 2 entry covers part of a single quantum, which only one handle maps pages into at a time.
+        create_table(sys, l2_entry);
+    }
+    let l1 = table(sys, (*l2_entry).frame().ok()?);
+    Some(l1.add(addr.p1_index().into()))
+}
+
 pub unsafe fn direct_access_map(
     sys: impl SystemInterface,
-    page: Page<Size2MiB>,
-    frame: PhysFrame<Size2MiB>,
+    page: Page<HeapPageSize>,
+    frame: PhysFrame<HeapPageSize>,
+) {
+    debug!("mapping {page:?} to {frame:?}");
+    let entry = &mut *find_leaf_entry(sys, page.start_address(), true).unwrap();
+    debug_assert!(entry.is_unused());
+    entry.set_addr(frame.start_address(), LEAF_FLAGS);
+}
+
+pub unsafe fn direct_access_unmap(
+    sys: impl SystemInterface,
+    page: Page<HeapPageSize>,
+) -> PhysFrame<HeapPageSize> {
+    let entry = find_leaf_entry(sys, page.start_address(), false)
+        .unwrap_unchecked()
+        .replace(PageTableEntry::new());
+    debug_assert!(entry.flags().contains(LEAF_FLAGS));
+    let frame = PhysFrame::from_start_address(entry.addr()).unwrap();
+    debug!("unmapped {page:?}, was {frame:?}");
+    frame
+}
+
+/// Leaf entries of consecutive pages are adjacent within a table.
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// Walks the page table once per leaf table touched.
+pub unsafe fn direct_access_map_range(
+    sys: impl SystemInterface,
+    start: Page<HeapPageSize>,
+    count: usize,
+    mut frames: impl FnMut() -> PhysFrame<HeapPageSize>,
+) {
+    debug!("mapping {count} pages from {start:?}");
+    let start = start.start_address().as_u64() as usize;
+    let mut i = 0;
+    while i < count {
+        let addr = start + i * PAGE_SIZE;
+        let entries = find_leaf_entry(sys, vaddr_unchecked(addr), true).unwrap();
+        let run = (count - i).min(ENTRIES_PER_TABLE - addr / PAGE_SIZE % ENTRIES_PER_TABLE);
+        for j in 0..run {
+            let entry = &mut *entries.add(j);
+            debug_assert!(entry.is_unused());
+            entry.set_addr(frames().start_address(), LEAF_FLAGS);
+        }
+        i += run;
+    }
+}
+
+/// Walks the page table once per leaf table touched.
+pub unsafe fn direct_access_unmap_range(
+    sys: impl SystemInterface,
+    start: Page<HeapPageSize>,
+    count: usize,
+    mut release: impl FnMut(PhysFrame<HeapPageSize>),
 ) {
+    let start = start.start_address().as_u64() as usize;
+    let mut i = 0;
+    while i < count {
+        let addr = start + i * PAGE_SIZE;
+        let entries = find_leaf_entry(sys, vaddr_unchecked(addr), false).unwrap_unchecked();
+        let run = (count - i).min(ENTRIES_PER_TABLE - addr / PAGE_SIZE % ENTRIES_PER_TABLE);
+        for j in 0..run {
+            let entry = entries.add(j).replace(PageTableEntry::new());
+            debug_assert!(entry.flags().contains(LEAF_FLAGS));
+            release(PhysFrame::from_start_address(entry.addr()).unwrap());
+        }
+        i += run;
+    }
+    debug!("unmapped {count} pages from {start:x}");
+}
+
+unsafe fn giant_entry(sys: impl SystemInterface, page: Page<Size1GiB>) -> *mut PageTableEntry {
+    let l3 = find_l3_table(sys, page.start_address(), false).unwrap_unchecked();
+    l3.add(page.p3_index().into())
+}
+
+pub unsafe fn direct_access_map_giant(
+    sys: impl SystemInterface,
+    page: Page<Size1GiB>,
+    frame: PhysFrame<Size1GiB>,
+) -> Option<PhysFrame<Size4KiB>> {
     debug!("mapping {page:?} to {frame:?}");
-    let (l4_frame, _) = Cr3::read();
-    let l4 = sys
-        .vaddr(l4_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l3_frame = l4.add(page.p4_index().into()).read().frame().unwrap();
-    let l3 = sys
-        .vaddr(l3_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l2_frame = l3.add(page.p3_index().into()).read().frame().unwrap();
-    let l2 = sys
-        .vaddr(l2_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l2_entry = &mut *l2.add(page.p2_index().into());
-    debug_assert!(l2_entry.is_unused());
-    l2_entry.set_addr(
+    let entry = &mut *giant_entry(sys, page);
+    let table = entry.frame().ok();
+    entry.set_addr(
         frame.start_address(),
         PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE,
     );
+    table
 }
-pub unsafe fn direct_access_unmap(
+
+pub unsafe fn direct_access_unmap_giant(
     sys: impl SystemInterface,
-    page: Page<Size2MiB>,
-) -> PhysFrame<Size2MiB> {
-    let (l4_frame, _) = Cr3::read();
-    let l4 = sys
-        .vaddr(l4_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l3_frame = l4
-        .add(page.p4_index().into())
-        .read()
-        .frame()
-        .unwrap_unchecked();
-    let l3 = sys
-        .vaddr(l3_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l2_frame = l3
-        .add(page.p3_index().into())
-        .read()
-        .frame()
-        .unwrap_unchecked();
-    let l2 = sys
-        .vaddr(l2_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    let l2_entry = l2
-        .add(page.p2_index().into())
-        .replace(PageTableEntry::new());
-    debug_assert!(l2_entry
+    page: Page<Size1GiB>,
+    table: Option<PhysFrame<Size4KiB>>,
+) -> PhysFrame<Size1GiB> {
+    let entry = &mut *giant_entry(sys, page);
+    debug_assert!(entry
         .flags()
         .contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE));
-    let frame = PhysFrame::from_start_address(l2_entry.addr()).unwrap();
+    let frame = PhysFrame::from_start_address(entry.addr()).unwrap();
+    match table {
+        Some(table) => entry.set_addr(
+            table.start_address(),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        ),
+        None => entry.set_unused(),
+    }
     debug!("unmapped {page:?}, was {frame:?}");
     frame
 }
@@ -144,60 +367,39 @@ pub fn direct_access_prepare_page_table(
     }
 
     let mut leaked_frames = 0;
-    let (l4_frame, _) = Cr3::read();
-    let l4 = sys
-        .vaddr(l4_frame.start_address())
-        .as_mut_ptr::<PageTableEntry>();
-    for i4 in usize::from(range.start.p4_index())..=usize::from(range.end.p4_index()) {
-        let l4_entry = unsafe { ensure_present(sys, l4.add(i4)).pte };
-        assert!(l4_entry.flags().contains(PageTableFlags::PRESENT));
-        let l3 = sys
-            .vaddr(unsafe { l4_entry.frame().unwrap_unchecked() }.start_address())
-            .as_mut_ptr::<PageTableEntry>();
-        let i3_start = if i4 == usize::from(range.start.p4_index()) {
-            usize::from(range.start.p3_index())
-        } else {
-            0
-        };
-        let i3_end = if i4 == usize::from(range.end.p4_index()) {
-            usize::from(range.end.p3_index())
-        } else {
-            511
-        };
-        for i3 in i3_start..=i3_end {
-            let EnsurePresent {
-                pte: l3_entry,
-                is_new,
-            } = unsafe { ensure_present(sys, l3.add(i3)) };
-            if !is_new {
-                let l2_frame = l3_entry.frame().unwrap();
-                let l2 = sys
-                    .vaddr(l2_frame.start_address())
-                    .as_mut_ptr::<PageTableEntry>();
-                let i2_start = if i3 == i3_start {
-                    usize::from(range.start.p2_index())
-                } else {
-                    0
-                };
-                let i2_end = if i3 == i3_end {
-                    usize::from(range.end.p2_index())
-                } else {
-                    511
-                };
-                for i2 in i2_start..=i2_end {
-                    unsafe {
-                        let l2e = &mut *(l2.add(i2));
-                        if l2e.flags().contains(PageTableFlags::PRESENT) {
-                            warn!("leaking frame {l2e:?}");
-                            leaked_frames += 1;
-                        }
-                        *l2e = PageTableEntry::new();
+    let end = range.end.start_address().as_u64() as usize + Size2MiB::SIZE as usize;
+    let mut addr = range.start.start_address().as_u64() as usize;
+    // one level 3 entry at a time, the tables above are created as needed.
+    while addr < end {
+        let chunk_end = end
+            .min(align_down_const::<{ Size1GiB::SIZE as usize }>(addr) + Size1GiB::SIZE as usize);
+        let vaddr = unsafe { vaddr_unchecked(addr) };
+        let l3 = unsafe { find_l3_table(sys, vaddr, true).unwrap() };
+        let EnsurePresent {
+            pte: l3_entry,
+            is_new,
+        } = unsafe { ensure_present(sys, l3.add(vaddr.p3_index().into())) };
+        if !is_new {
+            let l2_frame = l3_entry.frame().unwrap();
+            let l2 = sys
+                .vaddr(l2_frame.start_address())
+                .as_mut_ptr::<PageTableEntry>();
+            let i2_start = usize::from(vaddr.p2_index());
+            let i2_end = usize::from(unsafe { vaddr_unchecked(chunk_end - 1) }.p2_index());
+            for i2 in i2_start..=i2_end {
+                unsafe {
+                    let l2e = &mut *(l2.add(i2));
+                    if l2e.flags().contains(PageTableFlags::PRESENT) {
+                        warn!("leaking frame {l2e:?}");
+                        leaked_frames += 1;
                     }
+                    *l2e = PageTableEntry::new();
                 }
             }
         }
+        addr = chunk_end;
     }
-    sys.global_tlb_flush();
+    sys.flush_tlb_range(range.start.start_address().as_u64() as usize..end);
     if leaked_frames > 0 {
         warn!("leaked {leaked_frames} frames");
     }
@@ -12,29 +12,57 @@ macro_rules! unsafe_assert {
 
 pub(crate) use unsafe_assert;
 
+#[cfg(not(feature = "small_pages"))]
+use x86_64::structures::paging::Size2MiB;
+#[cfg(feature = "small_pages")]
+use x86_64::structures::paging::Size4KiB;
 use x86_64::{
-    structures::paging::{Page, Size2MiB},
+    structures::paging::{Page, PageSize},
     VirtAddr,
 };
 
+/// The size of the pages and frames making up the heap.
+#[cfg(not(feature = "small_pages"))]
+pub type HeapPageSize = Size2MiB;
+/// The size of the pages and frames making up the heap.
+#[cfg(feature = "small_pages")]
+pub type HeapPageSize = Size4KiB;
+
 pub const VIRTUAL_QUANTUM_BITS: u32 = 24;
 pub const VIRTUAL_QUANTUM_SIZE: usize = 1 << VIRTUAL_QUANTUM_BITS;
-pub const PAGE_SIZE_LOG: u32 = 21;
+pub const PAGE_SIZE_LOG: u32 = HeapPageSize::SIZE.trailing_zeros();
 pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_LOG;
 
-pub unsafe fn page_from_addr(addr: VirtAddr) -> Page<Size2MiB> {
-    if cfg!(debug_assertions) {
-        Page::from_start_address(addr).unwrap()
-    } else {
-        unsafe { Page::from_start_address_unchecked(addr) }
-    }
+/// [Page::from_start_address] sign extends addresses beyond 4-level paging before checking them.
+pub unsafe fn page_from_addr<P: PageSize>(addr: VirtAddr) -> Page<P> {
+    debug_assert!(addr.as_u64().is_multiple_of(P::SIZE));
+    unsafe { Page::from_start_address_unchecked(addr) }
 }
 
+/// [VirtAddr::new] rejects addresses that are only canonical with 5-level paging.
 pub unsafe fn vaddr_unchecked(addr: usize) -> VirtAddr {
-    if cfg!(debug_assertions) {
-        VirtAddr::new(addr as u64)
+    debug_assert!(is_canonical(addr));
+    VirtAddr::new_unsafe(addr as u64)
+}
+
+/// True if `addr` is canonical with 5-level paging, which includes all addresses canonical with 4 levels.
+pub fn is_canonical(addr: usize) -> bool {
+    let high = (addr as isize) >> 56;
+    high == 0 || high == -1
+}
+
+/// The end of the lower half of the address space translated by `paging_levels` levels of tables.
+pub const fn lower_half_end(paging_levels: u32) -> usize {
+    1 << (12 + 9 * paging_levels - 1)
+}
+
+/// The mmap address hint for an arena of `size` bytes.
+/// Kernels only map above 128TiB if the hint lies there, which arenas too large for four levels need.
+pub const fn arena_hint(size: usize) -> usize {
+    if size > lower_half_end(4) / 2 {
+        lower_half_end(4)
     } else {
-        VirtAddr::new_unsafe(addr as u64)
+        0
     }
 }
 
@@ -66,13 +94,3 @@ pub fn align_up_const<const ALIGN: usize>(a: usize) -> usize {
     assert!(ALIGN.is_power_of_two());
     a.next_multiple_of(ALIGN)
 }
-
-pub unsafe fn map_unreachable<A, B>() -> impl FnOnce(A) -> B {
-    |_| {
-        if cfg!(debug_assertions) {
-            panic!()
-        } else {
-            std::hint::unreachable_unchecked()
-        }
-    }
-}
@@ -11,13 +11,24 @@ extern "C" {
 void global_virtual_alloc_init(uint64_t physical_size, uint64_t virtual_size);
 
 // allocate `size` bytes of memory, aligned to `align` bytes.
+// allocations aligned to more than a page take up at least their alignment.
+// returns NULL if out of memory, or if the alignment exceeds the largest block of the virtual space.
 void * global_virtual_alloc_alloc(uint64_t size, uint64_t align);
 
 // deallocate memory.
 // The size and alignment must exactly match the values passed during allocation.
 void global_virtual_alloc_free(uint64_t size, uint64_t align, void *ptr);
 
+// returns free memory to the system until at most `target` bytes remain unused.
+// memory cached by threads is returned the next time they free memory, so a later call may release more.
+// returns the number of bytes released.
+uint64_t global_virtual_alloc_release_memory(uint64_t target);
+
+// with the `alloc_log` feature, every allocation and deallocation is recorded in a per-thread event log.
+// without it, these functions do nothing.
+// writes the events of all threads recorded since the last flush to `virtual_alloc_log_<id>.bin`.
 void global_virtual_alloc_flush_log(uint64_t id);
+// records an arbitrary value in the event log of the calling thread.
 void global_virtual_alloc_log_alloc(int64_t size);
 
 #ifdef __cplusplus
@@ -15,13 +15,25 @@ typedef struct{
 void virtual_alloc_init_global(uint64_t physical_size, uint64_t virtual_size);
 
 // Creates a handle to the allocator.
+// Returns false if the allocator has not been initialized.
 // A handle is bound to the thread it was created on and must not be accessed from other threads.
 // It is safe to move this handle around via `memcpy`.
-// Currently, destruction of handles is not implemented.
-// Leaking it leaks up to 2MiB of physical memory and 16MiB of virtual address space.
+// Leaking it leaks what virtual_alloc_destroy_handle releases:
+// up to `frame_cache_limit` cached pages, the page small objects are bump allocated from,
+// and every 16MiB quantum holding medium objects of the handle, whose free space only the handle reuses,
+// and whose footer page stays mapped after the objects are freed.
+// With size classes, the pages holding small objects of the handle are never released either,
+// which includes the possibly empty pages of up to `size_class_limit` size classes it allocates from.
 bool virtual_alloc_init_handle(VirtualAllocHandle *dst, uint64_t seed);
 
+// Destroys a handle, returning its cached memory to the allocator.
+// Memory allocated using the handle remains valid and may be deallocated using a different handle.
+// The handle must not be used afterwards.
+void virtual_alloc_destroy_handle(VirtualAllocHandle *local);
+
 // allocate `size` bytes of memory, aligned to `align` bytes.
+// allocations aligned to more than a page take up at least their alignment.
+// returns NULL if out of memory, or if the alignment exceeds the largest block of the virtual space.
 void *virtual_alloc_alloc(VirtualAllocHandle *local, uint64_t size, uint64_t align);
 
 // deallocate memory using a handle.